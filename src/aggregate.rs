@@ -0,0 +1,542 @@
+use crate::projection::Projection;
+use crate::query::{
+    arithmetic, compare_values, equality_key, invalid_query, parse_expression, Arithmetic,
+    Expression, QueryEngine, QueryOperator,
+};
+use crate::sort::{self, Sort};
+use pyo3::PyErr;
+use serde_json::{Map, Number, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+#[derive(Debug)]
+enum Stage {
+    Match(QueryEngine<QueryOperator>),
+    Project(Project),
+    Group(Group),
+    Sort(Sort),
+    Skip(usize),
+    Limit(usize),
+    Unwind(Unwind),
+    // Name of the field holding the number of documents
+    Count(String),
+}
+
+#[derive(Debug)]
+struct Project {
+    projection: Projection,
+    // Fields set to the value of an expression, like {"total": {"$add": ["$a", "$b"]}}
+    computed: Vec<(Vec<String>, Expression)>,
+}
+
+#[derive(Debug)]
+enum GroupKey {
+    Expression(Expression),
+    // {"_id": {"year": "$year", "month": "$month"}}
+    Fields(Vec<(String, Expression)>),
+}
+
+#[derive(Debug)]
+enum Accumulator {
+    Sum(Expression),
+    Avg(Expression),
+    Min(Expression),
+    Max(Expression),
+    Count,
+    Push(Expression),
+    First(Expression),
+    Last(Expression),
+}
+
+#[derive(Debug)]
+struct Group {
+    key: GroupKey,
+    accumulators: Vec<(String, Accumulator)>,
+}
+
+#[derive(Debug)]
+struct Unwind {
+    fields: Vec<String>,
+    preserve_null_and_empty_arrays: bool,
+    include_array_index: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn new(pipeline: &[Value]) -> Result<Self, PyErr> {
+        // Compiles a pipeline like [{"$match": {...}}, {"$group": {...}}]
+        let mut stages = Vec::with_capacity(pipeline.len());
+        for stage in pipeline {
+            let (name, spec) = match stage.as_object() {
+                Some(stage) if stage.len() == 1 => stage.iter().next().unwrap(),
+                _ => {
+                    return Err(invalid_query(
+                        &[],
+                        "a pipeline stage must be an object with a single stage name",
+                    ))
+                }
+            };
+            let path = [name.clone()];
+            stages.push(match (name.as_str(), spec) {
+                ("$match", Value::Object(query)) => {
+                    Stage::Match(QueryEngine::<QueryOperator>::new(query)?)
+                }
+                ("$project", Value::Object(projection)) => {
+                    Stage::Project(parse_project(projection)?)
+                }
+                ("$group", Value::Object(group)) => Stage::Group(parse_group(group)?),
+                ("$sort", sort) => Stage::Sort(parse_sort(sort)?),
+                ("$skip", Value::Number(n)) if n.as_u64().is_some() => {
+                    Stage::Skip(n.as_u64().unwrap() as usize)
+                }
+                ("$limit", Value::Number(n)) if n.as_u64().is_some_and(|limit| limit > 0) => {
+                    Stage::Limit(n.as_u64().unwrap() as usize)
+                }
+                ("$unwind", unwind) => Stage::Unwind(parse_unwind(unwind)?),
+                ("$count", Value::String(field))
+                    if !field.is_empty() && !field.starts_with('$') && !field.contains('.') =>
+                {
+                    Stage::Count(field.clone())
+                }
+                ("$match" | "$project" | "$group", _) => {
+                    return Err(invalid_query(&path, "the stage takes an object"))
+                }
+                ("$skip", _) => {
+                    return Err(invalid_query(&path, "$skip takes a non-negative integer"))
+                }
+                ("$limit", _) => {
+                    return Err(invalid_query(&path, "$limit takes a positive integer"))
+                }
+                ("$count", _) => {
+                    return Err(invalid_query(
+                        &path,
+                        "$count takes a field name without '$' or '.'",
+                    ))
+                }
+                _ => {
+                    return Err(invalid_query(
+                        &[],
+                        &format!("unknown pipeline stage {}", name),
+                    ))
+                }
+            });
+        }
+        Ok(Pipeline { stages })
+    }
+
+    pub fn execute(&self, collection: &[Value]) -> Result<Vec<Value>, PyErr> {
+        let mut stages = self.stages.iter().peekable();
+        // A leading $match copies only the matching documents
+        let mut documents: Vec<Value> = match stages.peek() {
+            Some(Stage::Match(engine)) => {
+                stages.next();
+                let mut matched = Vec::new();
+                for document in collection {
+                    if matches(engine, document)? {
+                        matched.push(document.clone());
+                    }
+                }
+                matched
+            }
+            _ => collection.to_vec(),
+        };
+        while let Some(stage) = stages.next() {
+            documents = match (stage, stages.peek()) {
+                // A $sort followed by a $limit only keeps the first documents
+                (Stage::Sort(sort), Some(Stage::Limit(limit))) => {
+                    stages.next();
+                    sort_documents(documents, sort, Some(*limit))
+                }
+                _ => stage.execute(documents)?,
+            };
+        }
+        Ok(documents)
+    }
+}
+
+impl Stage {
+    fn execute(&self, documents: Vec<Value>) -> Result<Vec<Value>, PyErr> {
+        Ok(match self {
+            Stage::Match(engine) => {
+                let mut matched = Vec::new();
+                for document in documents {
+                    if matches(engine, &document)? {
+                        matched.push(document);
+                    }
+                }
+                matched
+            }
+            Stage::Project(project) => documents
+                .iter()
+                .map(|document| project.apply(document))
+                .collect::<Result<_, _>>()?,
+            Stage::Group(group) => group.apply(&documents)?,
+            Stage::Sort(sort) => sort_documents(documents, sort, None),
+            Stage::Skip(skip) => documents.into_iter().skip(*skip).collect(),
+            Stage::Limit(limit) => documents.into_iter().take(*limit).collect(),
+            Stage::Unwind(unwind) => documents
+                .into_iter()
+                .flat_map(|document| unwind.apply(document))
+                .collect(),
+            Stage::Count(field) => {
+                // Like MongoDB, nothing is returned when there are no documents
+                if documents.is_empty() {
+                    return Ok(Vec::new());
+                }
+                let mut count = Map::new();
+                count.insert(field.clone(), Value::from(documents.len()));
+                vec![Value::Object(count)]
+            }
+        })
+    }
+}
+
+impl Project {
+    fn apply(&self, document: &Value) -> Result<Value, PyErr> {
+        let mut projected = self.projection.apply(document);
+        if let (Value::Object(obj), Some(projected_obj)) = (document, projected.as_object_mut()) {
+            for (fields, expression) in &self.computed {
+                set_path(projected_obj, fields, expression.evaluate(obj)?);
+            }
+        }
+        Ok(projected)
+    }
+}
+
+impl Group {
+    fn apply(&self, documents: &[Value]) -> Result<Vec<Value>, PyErr> {
+        // Groups are returned in the order their first document was found.
+        // Keys are hashed through equality_key, so 2 and 2.0 are one group
+        let mut keys: Vec<Value> = Vec::new();
+        let mut members: Vec<Vec<&Map<String, Value>>> = Vec::new();
+        let mut positions: HashMap<Value, usize> = HashMap::new();
+        let empty = Map::new();
+        for document in documents {
+            let obj = document.as_object().unwrap_or(&empty);
+            let key = self.key.evaluate(obj)?;
+            let position = *positions.entry(equality_key(&key)).or_insert_with(|| {
+                keys.push(key);
+                members.push(Vec::new());
+                members.len() - 1
+            });
+            members[position].push(obj);
+        }
+        let mut groups = Vec::with_capacity(keys.len());
+        for (key, members) in keys.into_iter().zip(members) {
+            let mut group = Map::new();
+            group.insert("_id".to_string(), key);
+            for (field, accumulator) in &self.accumulators {
+                group.insert(field.clone(), accumulator.accumulate(&members)?);
+            }
+            groups.push(Value::Object(group));
+        }
+        Ok(groups)
+    }
+}
+
+impl GroupKey {
+    fn evaluate(&self, document: &Map<String, Value>) -> Result<Value, PyErr> {
+        match self {
+            GroupKey::Expression(expression) => expression.evaluate(document),
+            GroupKey::Fields(fields) => {
+                let mut key = Map::new();
+                for (field, expression) in fields {
+                    key.insert(field.clone(), expression.evaluate(document)?);
+                }
+                Ok(Value::Object(key))
+            }
+        }
+    }
+}
+
+impl Accumulator {
+    fn accumulate(&self, documents: &[&Map<String, Value>]) -> Result<Value, PyErr> {
+        let values = |expression: &Expression| {
+            documents
+                .iter()
+                .map(|document| expression.evaluate(document))
+                .collect::<Result<Vec<Value>, PyErr>>()
+        };
+        Ok(match self {
+            // Values that are not numbers are ignored by $sum and $avg
+            Accumulator::Sum(expression) => {
+                let mut sum = Number::from(0);
+                for value in values(expression)? {
+                    if let Value::Number(number) = value {
+                        match arithmetic(Arithmetic::Add, &sum, &number) {
+                            Some(result) => sum = result,
+                            None => return Ok(Value::Null),
+                        }
+                    }
+                }
+                Value::Number(sum)
+            }
+            Accumulator::Avg(expression) => {
+                let numbers: Vec<f64> = values(expression)?
+                    .iter()
+                    .filter_map(|value| value.as_f64())
+                    .collect();
+                if numbers.is_empty() {
+                    return Ok(Value::Null);
+                }
+                let avg = numbers.iter().sum::<f64>() / numbers.len() as f64;
+                Number::from_f64(avg).map_or(Value::Null, Value::Number)
+            }
+            // Null and missing values are ignored by $min and $max
+            Accumulator::Min(expression) | Accumulator::Max(expression) => {
+                let wanted = match self {
+                    Accumulator::Min(_) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                values(expression)?
+                    .into_iter()
+                    .filter(|value| !value.is_null())
+                    .reduce(
+                        |best, value| match compare_values(&value, &best) == wanted {
+                            true => value,
+                            false => best,
+                        },
+                    )
+                    .unwrap_or(Value::Null)
+            }
+            Accumulator::Count => Value::from(documents.len()),
+            Accumulator::Push(expression) => Value::Array(values(expression)?),
+            Accumulator::First(expression) => match documents.first() {
+                Some(document) => expression.evaluate(document)?,
+                None => Value::Null,
+            },
+            Accumulator::Last(expression) => match documents.last() {
+                Some(document) => expression.evaluate(document)?,
+                None => Value::Null,
+            },
+        })
+    }
+}
+
+impl Unwind {
+    fn apply(&self, document: Value) -> Vec<Value> {
+        // One document per element of the array at fields
+        let elements = match lookup(&document, &self.fields) {
+            Some(Value::Array(elements)) => elements.clone(),
+            // A value that is not an array is unwound like a single element array
+            Some(value) if !value.is_null() => vec![value.clone()],
+            _ => Vec::new(),
+        };
+        if elements.is_empty() {
+            if !self.preserve_null_and_empty_arrays {
+                return Vec::new();
+            }
+            let mut document = document;
+            if let Some(index_field) = &self.include_array_index {
+                if let Some(obj) = document.as_object_mut() {
+                    obj.insert(index_field.clone(), Value::Null);
+                }
+            }
+            return vec![document];
+        }
+        elements
+            .into_iter()
+            .enumerate()
+            .map(|(i, element)| {
+                let mut unwound = document.clone();
+                if let Some(obj) = unwound.as_object_mut() {
+                    set_path(obj, &self.fields, element);
+                    if let Some(index_field) = &self.include_array_index {
+                        obj.insert(index_field.clone(), Value::from(i));
+                    }
+                }
+                unwound
+            })
+            .collect()
+    }
+}
+
+fn sort_documents(documents: Vec<Value>, sort: &Sort, limit: Option<usize>) -> Vec<Value> {
+    let positions = sort::select(&documents, Some(sort), 0, limit);
+    let mut documents: Vec<Option<Value>> = documents.into_iter().map(Some).collect();
+    positions
+        .into_iter()
+        .map(|i| documents[i].take().unwrap())
+        .collect()
+}
+
+fn matches(engine: &QueryEngine<QueryOperator>, document: &Value) -> Result<bool, PyErr> {
+    match document.as_object() {
+        Some(obj) => engine.execute(obj),
+        None => Ok(false),
+    }
+}
+
+fn parse_project(projection: &Map<String, Value>) -> Result<Project, PyErr> {
+    // 0, 1 and $slice are projections, anything else is an expression
+    let mut fields = Map::new();
+    let mut computed = Vec::new();
+    for (key, value) in projection {
+        let is_slice = value
+            .as_object()
+            .is_some_and(|obj| obj.contains_key("$slice"));
+        if value.is_number() || value.is_boolean() || is_slice {
+            fields.insert(key.clone(), value.clone());
+        } else {
+            let path: Vec<String> = key.split('.').map(|k| k.to_string()).collect();
+            computed.push((path, parse_expression(value)?));
+        }
+    }
+    // Computed fields are only added to the fields that are included
+    let projection = match computed.is_empty() {
+        true => Projection::new(&fields)?,
+        false => Projection::inclusion(&fields)?,
+    };
+    Ok(Project {
+        projection,
+        computed,
+    })
+}
+
+fn parse_group(group: &Map<String, Value>) -> Result<Group, PyErr> {
+    let key = match group.get("_id") {
+        Some(Value::Object(fields)) if !fields.keys().any(|k| k.starts_with('$')) => {
+            GroupKey::Fields(
+                fields
+                    .iter()
+                    .map(|(field, expression)| Ok((field.clone(), parse_expression(expression)?)))
+                    .collect::<Result<_, PyErr>>()?,
+            )
+        }
+        Some(expression) => GroupKey::Expression(parse_expression(expression)?),
+        None => return Err(invalid_query(&["$group".to_string()], "_id is required")),
+    };
+    let mut accumulators = Vec::new();
+    for (field, accumulator) in group {
+        if field == "_id" {
+            continue;
+        }
+        let path = [field.clone()];
+        let (operator, argument) = match accumulator.as_object() {
+            Some(accumulator) if accumulator.len() == 1 => accumulator.iter().next().unwrap(),
+            _ => {
+                return Err(invalid_query(
+                    &path,
+                    "an accumulator must be an object with a single operator",
+                ))
+            }
+        };
+        let expression = || parse_expression(argument);
+        accumulators.push((
+            field.clone(),
+            match operator.as_str() {
+                "$sum" => Accumulator::Sum(expression()?),
+                "$avg" => Accumulator::Avg(expression()?),
+                "$min" => Accumulator::Min(expression()?),
+                "$max" => Accumulator::Max(expression()?),
+                "$count" => Accumulator::Count,
+                "$push" => Accumulator::Push(expression()?),
+                "$first" => Accumulator::First(expression()?),
+                "$last" => Accumulator::Last(expression()?),
+                _ => {
+                    return Err(invalid_query(
+                        &path,
+                        &format!("unknown accumulator {}", operator),
+                    ))
+                }
+            },
+        ));
+    }
+    Ok(Group { key, accumulators })
+}
+
+fn parse_sort(sort: &Value) -> Result<Sort, PyErr> {
+    // Sort fields in order, either [["a", 1], ["b", -1]] or {"a": 1}
+    let invalid = || {
+        invalid_query(
+            &["$sort".to_string()],
+            "$sort takes fields with a direction of 1 or -1",
+        )
+    };
+    let pairs: Option<Vec<(String, i64)>> = match sort {
+        Value::Object(fields) if !fields.is_empty() => fields
+            .iter()
+            .map(|(field, direction)| Some((field.clone(), direction.as_i64()?)))
+            .collect(),
+        Value::Array(pairs) if !pairs.is_empty() => pairs
+            .iter()
+            .map(|pair| match pair.as_array()?.as_slice() {
+                [Value::String(field), direction] => Some((field.clone(), direction.as_i64()?)),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    };
+    Sort::new(&pairs.ok_or_else(invalid)?)
+}
+
+fn parse_unwind(unwind: &Value) -> Result<Unwind, PyErr> {
+    // "$items" or {"path": "$items", "preserveNullAndEmptyArrays": true}
+    let invalid = || {
+        invalid_query(
+            &["$unwind".to_string()],
+            "$unwind takes a field path starting with '$'",
+        )
+    };
+    let (path, options) = match unwind {
+        Value::String(path) => (path, None),
+        Value::Object(options) => match options.get("path") {
+            Some(Value::String(path)) => (path, Some(options)),
+            _ => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+    };
+    let Some(path) = path.strip_prefix('$').filter(|path| !path.is_empty()) else {
+        return Err(invalid());
+    };
+    let mut preserve_null_and_empty_arrays = false;
+    let mut include_array_index = None;
+    for (option, value) in options.into_iter().flatten() {
+        match (option.as_str(), value) {
+            ("path", _) => {}
+            ("preserveNullAndEmptyArrays", Value::Bool(preserve)) => {
+                preserve_null_and_empty_arrays = *preserve
+            }
+            ("includeArrayIndex", Value::String(field)) if !field.starts_with('$') => {
+                include_array_index = Some(field.clone())
+            }
+            _ => {
+                return Err(invalid_query(
+                    &["$unwind".to_string()],
+                    &format!("invalid option {}", option),
+                ))
+            }
+        }
+    }
+    Ok(Unwind {
+        fields: path.split('.').map(|k| k.to_string()).collect(),
+        preserve_null_and_empty_arrays,
+        include_array_index,
+    })
+}
+
+fn lookup<'a>(value: &'a Value, fields: &[String]) -> Option<&'a Value> {
+    fields
+        .iter()
+        .try_fold(value, |current, key| current.as_object()?.get(key))
+}
+
+fn set_path(obj: &mut Map<String, Value>, fields: &[String], value: Value) {
+    // Sets a dotted path, replacing anything that is not an object on the way
+    let (key, rest) = fields.split_first().unwrap();
+    if rest.is_empty() {
+        obj.insert(key.clone(), value);
+        return;
+    }
+    let nested = obj
+        .entry(key.clone())
+        .or_insert_with(|| Value::Object(Map::new()));
+    if !nested.is_object() {
+        *nested = Value::Object(Map::new());
+    }
+    set_path(nested.as_object_mut().unwrap(), rest, value);
+}
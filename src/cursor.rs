@@ -0,0 +1,77 @@
+use crate::projection::Projection;
+use crate::{collection_to_python, to_python};
+use pyo3::prelude::*;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+use std::vec;
+
+#[pyclass]
+pub struct Cursor {
+    // Snapshot of the found documents, which later updates do not change
+    documents: Arc<RwLock<Vec<Value>>>,
+    // Positions in documents that are still to be returned
+    positions: vec::IntoIter<usize>,
+    projection: Option<Projection>,
+    // Documents are returned in lists of batch_size if set
+    batch_size: Option<usize>,
+}
+
+impl Cursor {
+    pub fn new(
+        documents: Arc<RwLock<Vec<Value>>>,
+        positions: Vec<usize>,
+        projection: Option<Projection>,
+        batch_size: Option<usize>,
+    ) -> Self {
+        Cursor {
+            documents,
+            positions: positions.into_iter(),
+            projection,
+            batch_size,
+        }
+    }
+
+    fn next_document(&mut self, documents: &[Value]) -> Option<Value> {
+        let document = &documents[self.positions.next()?];
+        Some(match &self.projection {
+            Some(projection) => projection.apply(document),
+            None => document.clone(),
+        })
+    }
+}
+
+#[pymethods]
+impl Cursor {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let snapshot = self.documents.clone();
+        let documents = snapshot.read().unwrap();
+        let Some(batch_size) = self.batch_size else {
+            return match self.next_document(&documents) {
+                Some(document) => Ok(Some(to_python(py, &document)?)),
+                None => Ok(None),
+            };
+        };
+        let batch: Vec<Value> = (0..batch_size)
+            .map_while(|_| self.next_document(&documents))
+            .collect();
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(collection_to_python(py, &batch)?))
+    }
+
+    fn __len__(&self) -> usize {
+        // Number of documents that have not been returned yet
+        self.positions.len()
+    }
+
+    pub fn close(&mut self) {
+        // Releases the snapshot, the cursor returns no more documents
+        self.documents = Arc::new(RwLock::new(Vec::new()));
+        self.positions = Vec::new().into_iter();
+    }
+}
@@ -1,13 +1,22 @@
+// The #[pymethods] wrappers of pyo3 0.22 convert argument errors from PyErr
+// into PyErr. They are generated outside the impl block, so the lint cannot
+// be allowed any closer to them
+#![allow(clippy::useless_conversion)]
+use aggregate::Pipeline;
+use cursor::Cursor;
 use lru::LruCache;
+use num_bigint::BigInt;
+use projection::Projection;
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 use pyo3::types::{PyDict, PyList};
 use pyo3::PyErr;
 use pyo3::PyObject;
-use pythonize::{depythonize, pythonize};
-use query::{QueryOperator, UpdateOperator};
+use pythonize::depythonize;
+use query::{InvalidQueryError, QueryOperator, UpdateError, UpdateOperator};
 use serde_json::{Map, Value};
-use std::collections::HashMap;
+use sort::Sort;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::rename;
@@ -19,8 +28,15 @@ use std::io::BufWriter;
 use std::io::{BufReader, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use uuid::Uuid;
 
+mod aggregate;
+mod cursor;
+mod projection;
 mod query;
+mod sort;
+
+type SharedDocuments = Arc<RwLock<Vec<Value>>>;
 
 #[derive(Debug)]
 #[pyclass]
@@ -28,6 +44,8 @@ pub struct Bison {
     base_path: PathBuf,
     collections: HashMap<String, Arc<RwLock<Vec<Value>>>>,
     query_cache: LruCache<u64, Arc<RwLock<Vec<Value>>>>,
+    // Position of every document by _id, built on first use per collection
+    id_index: HashMap<String, HashMap<Value, usize>>,
 }
 impl Bison {
     fn get_collection_path(&self, collection_name: &str) -> PathBuf {
@@ -53,8 +71,9 @@ impl Bison {
         let reader = BufReader::new(file);
 
         // Parse the file into a serde_json::Value
-        let json_value: Value = serde_json::from_reader(reader)
+        let mut json_value: Value = serde_json::from_reader(reader)
             .map_err(|_| PyErr::new::<PyValueError, _>("Error deserializing JSON"))?;
+        query::normalize_numbers(&mut json_value);
         Ok(json_value)
     }
     fn update_in_memory_collections(&mut self, collection_name: &str) -> Result<(), PyErr> {
@@ -101,27 +120,76 @@ impl Bison {
         &mut self,
         collection_name: &str,
         insert_value: Value,
-    ) -> Result<(), PyErr> {
+        assign_ids: bool,
+    ) -> Result<Vec<Value>, PyErr> {
         // Create collection if it does not exist
         if !self.collections.contains_key(collection_name) {
             let _ = self.create_collection(collection_name);
         }
+        // Extend the collection if the value to insert is an array
+        let mut documents = match insert_value {
+            Value::Array(documents) => documents,
+            document => vec![document],
+        };
+        if !assign_ids {
+            // Documents are stored as they are, so positions are rebuilt on demand
+            self.id_index.remove(collection_name);
+            let collection_arc = self.collections.get(collection_name).unwrap();
+            collection_arc.write().unwrap().extend(documents);
+            return Ok(Vec::new());
+        }
 
+        self.id_positions(collection_name)?;
+        let id_positions = self.id_index.get_mut(collection_name).unwrap();
         let collection_arc = self.collections.get(collection_name).unwrap();
-
-        {
-            let mut collection = collection_arc.write().unwrap();
-            // Extend the collection if the value to insert is an array
-            if let Some(insert_value_arr) = insert_value.as_array() {
-                collection.extend_from_slice(insert_value_arr)
-            } else {
-                collection.push(insert_value);
+        let mut collection = collection_arc.write().unwrap();
+        // Check every id before inserting, so a duplicate does not leave
+        // the collection half inserted
+        let mut ids: Vec<Value> = Vec::with_capacity(documents.len());
+        let mut batch_ids: HashSet<Value> = HashSet::with_capacity(documents.len());
+        for document in documents.iter_mut() {
+            let id = match document.as_object_mut() {
+                Some(obj) => obj.entry("_id").or_insert_with(new_id).clone(),
+                // Only objects can have an _id
+                None => Value::Null,
+            };
+            if !id.is_null() && (id_positions.contains_key(&id) || !batch_ids.insert(id.clone())) {
+                return Err(PyErr::new::<PyValueError, _>(format!(
+                    "Document with _id {} already exists in collection '{}'",
+                    id, collection_name
+                )));
+            }
+            ids.push(id);
+        }
+        for (id, document) in ids.iter().zip(documents) {
+            if !id.is_null() {
+                id_positions.insert(id.clone(), collection.len());
             }
+            collection.push(document);
         }
+        Ok(ids)
+    }
 
-        self.collections
-            .insert(collection_name.to_string(), collection_arc.clone());
-        Ok(())
+    fn id_positions(&mut self, collection_name: &str) -> Result<&HashMap<Value, usize>, PyErr> {
+        if !self.id_index.contains_key(collection_name) {
+            let in_memory_collection = self.collections.get(collection_name);
+            let collection_arc = match in_memory_collection {
+                Some(c) => c,
+                None => match self.update_in_memory_collections(collection_name) {
+                    Ok(_) => self.collections.get(collection_name).unwrap(),
+                    Err(err) => return Err(err),
+                },
+            };
+            let mut id_positions = HashMap::new();
+            for (i, document) in collection_arc.read().unwrap().iter().enumerate() {
+                if let Some(id) = document.get("_id") {
+                    id_positions.entry(id.clone()).or_insert(i);
+                }
+            }
+            self.id_index
+                .insert(collection_name.to_string(), id_positions);
+        }
+        Ok(self.id_index.get(collection_name).unwrap())
     }
 
     fn _find(
@@ -141,7 +209,7 @@ impl Bison {
         };
 
         let query: Value = match maybe_query {
-            Some(q) => depythonize(q).unwrap(),
+            Some(q) => depythonize_query(q)?,
             None => {
                 // If there is no query, return all the values
                 return Ok(collection_arc.clone());
@@ -155,7 +223,7 @@ impl Bison {
         if let Some(cached_collections) = self.query_cache.get(&query_hash) {
             return Ok(cached_collections.clone());
         }
-        let query_engine = query::QueryEngine::<QueryOperator>::new(query_object);
+        let query_engine = query::QueryEngine::<QueryOperator>::new(query_object)?;
         // execute queries and return collections
         let mut found_collections: Vec<Value> = vec![];
         let read_collections = collection_arc.read().unwrap();
@@ -176,12 +244,51 @@ impl Bison {
             .put(query_hash, found_collections_arc.clone());
         Ok(found_collections_arc)
     }
+    fn _collection(&mut self, collection_name: &str) -> Result<SharedDocuments, PyErr> {
+        // Collection in memory, loaded from disk the first time it is used
+        if !self.collections.contains_key(collection_name) {
+            self.update_in_memory_collections(collection_name)?;
+        }
+        Ok(self.collections.get(collection_name).unwrap().clone())
+    }
+
+    fn _select(
+        &mut self,
+        collection_name: &str,
+        maybe_query: Option<&Bound<'_, PyDict>>,
+        sort: Option<Vec<(String, i64)>>,
+        limit: Option<usize>,
+        skip: usize,
+    ) -> Result<(SharedDocuments, Vec<usize>), PyErr> {
+        // Found documents and the positions of the requested page of them
+        let sort = match sort {
+            Some(s) => Some(Sort::new(&s)?),
+            None => None,
+        };
+        let found_collections = self._find(collection_name, maybe_query)?;
+        let positions = sort::select(
+            &found_collections.read().unwrap(),
+            sort.as_ref(),
+            skip,
+            limit,
+        );
+        Ok((found_collections, positions))
+    }
     fn _update(
         &mut self,
         collection_name: &str,
         py_update_query: &Bound<'_, PyDict>,
         maybe_filter_query: Option<&Bound<'_, PyDict>>,
+        upsert: bool,
+        maybe_array_filters: Option<&Bound<'_, PyList>>,
     ) -> Result<Arc<RwLock<Vec<Value>>>, PyErr> {
+        // Upserts create the collection like inserts do
+        if upsert
+            && !self.collections.contains_key(collection_name)
+            && self.update_in_memory_collections(collection_name).is_err()
+        {
+            self.create_collection(collection_name)?;
+        }
         let in_memory_collection = self.collections.get(collection_name);
         let collection_values_arc = match in_memory_collection {
             Some(c) => c,
@@ -190,36 +297,110 @@ impl Bison {
                 Err(err) => return Err(err),
             },
         };
+        let update_query: Value = depythonize_ordered(py_update_query)?;
+        let update_query_engine =
+            query::QueryEngine::<UpdateOperator>::new(update_query.as_object().unwrap())?;
+        let filter_query: Option<Value> = match maybe_filter_query {
+            Some(q) => Some(depythonize_query(q)?),
+            None => None,
+        };
+        let filter_query_engine = match &filter_query {
+            Some(q) => Some(query::QueryEngine::<QueryOperator>::new(
+                q.as_object().unwrap(),
+            )?),
+            None => None,
+        };
+        let array_filters: Vec<Value> = match maybe_array_filters {
+            Some(filters) => match depythonize_query(filters)? {
+                Value::Array(filters) => filters,
+                _ => Vec::new(),
+            },
+            None => Vec::new(),
+        };
+        let array_filters = query::ArrayFilters::new(&array_filters, &update_query_engine)?;
         {
             let mut collection_values = collection_values_arc.write().unwrap();
-            let update_query: Value = depythonize(py_update_query).unwrap();
-            let update_query_object: &Map<String, Value> = update_query.as_object().unwrap();
-            let update_query_engine =
-                query::QueryEngine::<UpdateOperator>::new(update_query_object);
-            match maybe_filter_query {
-                Some(q) => {
-                    let filter_query: Value = depythonize(q).unwrap();
-                    let filter_query_object: &Map<String, Value> =
-                        filter_query.as_object().unwrap();
-                    let filter_query_engine =
-                        query::QueryEngine::<QueryOperator>::new(filter_query_object);
-                    collection_values.iter_mut().for_each(|c| {
-                        let c_obj = c.as_object_mut().unwrap();
-                        if filter_query_engine.execute(c_obj).unwrap() {
-                            // Do update
-                            update_query_engine.execute(c_obj)
-                        }
-                    })
+            // Run the filter on every document before updating any of them,
+            // so an error does not leave the collection half updated
+            let mut matches: Vec<bool> = Vec::with_capacity(collection_values.len());
+            for c in collection_values.iter() {
+                let c_obj = c.as_object().unwrap();
+                matches.push(match &filter_query_engine {
+                    Some(engine) => engine.execute(c_obj)?,
+                    None => true,
+                });
+            }
+            // Updates are applied to copies and only stored once every
+            // document could be updated
+            let mut updated_collections: Vec<(usize, Value)> = Vec::new();
+            if upsert && !matches.contains(&true) {
+                // Nothing matched, insert a document built from the filter and the update
+                let mut document =
+                    update_query_engine.upsert(filter_query_engine.as_ref(), &array_filters)?;
+                let id = document.entry("_id").or_insert_with(new_id);
+                if collection_values.iter().any(|c| c.get("_id") == Some(id)) {
+                    return Err(PyErr::new::<PyValueError, _>(format!(
+                        "Document with _id {} already exists in collection '{}'",
+                        id, collection_name
+                    )));
                 }
-                None => collection_values.iter_mut().for_each(|c| {
-                    let c_obj = c.as_object_mut().unwrap();
-                    update_query_engine.execute(c_obj)
-                }),
-            };
+                collection_values.push(Value::Object(document));
+            }
+            for (i, (c, matched)) in collection_values.iter().zip(matches).enumerate() {
+                if matched {
+                    let mut updated = c.clone();
+                    update_query_engine
+                        .execute(
+                            updated.as_object_mut().unwrap(),
+                            filter_query_engine.as_ref(),
+                            &array_filters,
+                        )
+                        .map_err(|failure| failure.into_py_err(c))?;
+                    updated_collections.push((i, updated));
+                }
+            }
+            for (i, updated) in updated_collections {
+                collection_values[i] = updated;
+            }
         }
         Ok(collection_values_arc.clone())
     }
 
+    fn _delete(
+        &mut self,
+        collection_name: &str,
+        py_filter_query: &Bound<'_, PyDict>,
+        limit: Option<usize>,
+    ) -> Result<usize, PyErr> {
+        // Removes the documents matching the filter, at most limit of them
+        let in_memory_collection = self.collections.get(collection_name);
+        let collection_values_arc = match in_memory_collection {
+            Some(c) => c,
+            None => match self.update_in_memory_collections(collection_name) {
+                Ok(_) => self.collections.get(collection_name).unwrap(),
+                Err(err) => return Err(err),
+            },
+        };
+        let filter_query: Value = depythonize_query(py_filter_query)?;
+        let filter_query_engine =
+            query::QueryEngine::<QueryOperator>::new(filter_query.as_object().unwrap())?;
+        let mut collection_values = collection_values_arc.write().unwrap();
+        // Run the filter on every document before removing any of them
+        let mut matches: Vec<bool> = Vec::with_capacity(collection_values.len());
+        let mut deleted_count = 0;
+        for c in collection_values.iter() {
+            let matched = limit.is_none_or(|limit| deleted_count < limit)
+                && filter_query_engine.execute(c.as_object().unwrap())?;
+            if matched {
+                deleted_count += 1;
+            }
+            matches.push(matched);
+        }
+        let mut matches = matches.into_iter();
+        collection_values.retain(|_| !matches.next().unwrap());
+        Ok(deleted_count)
+    }
+
     fn _write(
         &self,
         collection_name: &str,
@@ -276,6 +457,7 @@ impl Bison {
             base_path: base_path.clone(),
             collections,
             query_cache,
+            id_index: HashMap::new(),
         };
         if !base_path.exists() {
             let _ = fs::create_dir(&base_path);
@@ -315,7 +497,7 @@ impl Bison {
             .unwrap()
             .to_owned();
         for (key, value) in document {
-            self.insert_in_collection(&key, value)?
+            self.insert_in_collection(&key, value, false)?;
         }
         Ok(())
     }
@@ -340,25 +522,27 @@ impl Bison {
         &mut self,
         collection_name: String,
         document: &Bound<'_, PyDict>,
-    ) -> PyResult<()> {
-        let obj: Value = depythonize(document).unwrap();
-        self.insert_in_collection(&collection_name, obj)
+    ) -> PyResult<PyObject> {
+        let obj: Value = depythonize_document(document)?;
+        let ids = self.insert_in_collection(&collection_name, obj, true)?;
+        Python::with_gil(|py| to_python(py, &ids[0]))
     }
 
     pub fn insert_many(
         &mut self,
         collection_name: String,
         documents: &Bound<'_, PyList>,
-    ) -> PyResult<()> {
-        let obj: Value = depythonize(documents).unwrap();
-        self.insert_in_collection(&collection_name, obj)
+    ) -> PyResult<PyObject> {
+        let obj: Value = depythonize_document(documents)?;
+        let ids = self.insert_in_collection(&collection_name, obj, true)?;
+        Python::with_gil(|py| collection_to_python(py, &ids))
     }
 
     pub fn insert_many_from_document(
         &mut self,
         collection_name: String,
         document_name: String,
-    ) -> PyResult<()> {
+    ) -> PyResult<PyObject> {
         // Insert many from json (array document)
         // The top most object in the json document
         // should be an array
@@ -366,33 +550,53 @@ impl Bison {
         match values.as_array() {
             // Here we do not insert the array as we are making that distinction in
             // Bison::insert_in_collection already
-            Some(_) => self.insert_in_collection(&collection_name, values),
+            Some(_) => {
+                let ids = self.insert_in_collection(&collection_name, values, true)?;
+                Python::with_gil(|py| collection_to_python(py, &ids))
+            }
             None => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
                 "Document is not an array",
             )),
         }
     }
 
-    #[pyo3(signature = (collection_name, maybe_query = None))]
+    #[pyo3(signature = (collection_name, maybe_query = None, projection = None, sort = None, limit = None, skip = 0))]
     pub fn find(
         &mut self,
         collection_name: String,
         maybe_query: Option<&Bound<'_, PyDict>>,
+        projection: Option<&Bound<'_, PyDict>>,
+        sort: Option<Vec<(String, i64)>>,
+        limit: Option<usize>,
+        skip: usize,
     ) -> PyResult<PyObject> {
-        let found_collections = self._find(&collection_name, maybe_query)?;
+        let projection = match projection {
+            Some(p) => Some(Projection::new(depythonize_query(p)?.as_object().unwrap())?),
+            None => None,
+        };
+        let (found_collections, positions) =
+            self._select(&collection_name, maybe_query, sort, limit, skip)?;
 
         let py_collections = {
             let mut result: Option<PyObject> = None;
             let mut py_error: Option<PyErr> = None;
 
             Python::with_gil(|py| {
-                match pythonize(py, found_collections.as_ref()) {
-                    Ok(obj) => {
-                        // Convert &PyAny to PyObject
-                        let py_obj = obj.to_object(py);
-                        result = Some(py_obj);
+                let found_collections = found_collections.read().unwrap();
+                let documents = positions.iter().map(|i| &found_collections[*i]);
+                // Projections are applied before the conversion to Python objects
+                let converted = match &projection {
+                    Some(projection) => {
+                        let projected: Vec<Value> = documents
+                            .map(|document| projection.apply(document))
+                            .collect();
+                        collection_to_python(py, &projected)
                     }
-                    Err(err) => py_error = Some(err.into()),
+                    None => collection_to_python(py, documents),
+                };
+                match converted {
+                    Ok(obj) => result = Some(obj),
+                    Err(err) => py_error = Some(err),
                 }
             });
 
@@ -405,20 +609,166 @@ impl Bison {
         Ok(py_collections)
     }
 
-    #[pyo3(signature = (collection_name, update_query, maybe_query = None, return_result=false))]
+    #[pyo3(signature = (collection_name, maybe_query = None, projection = None, sort = None, limit = None, skip = 0, batch_size = None))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_iter(
+        &mut self,
+        collection_name: String,
+        maybe_query: Option<&Bound<'_, PyDict>>,
+        projection: Option<&Bound<'_, PyDict>>,
+        sort: Option<Vec<(String, i64)>>,
+        limit: Option<usize>,
+        skip: usize,
+        batch_size: Option<usize>,
+    ) -> PyResult<Cursor> {
+        if batch_size == Some(0) {
+            return Err(PyErr::new::<PyValueError, _>("batch_size must be positive"));
+        }
+        let projection = match projection {
+            Some(p) => Some(Projection::new(depythonize_query(p)?.as_object().unwrap())?),
+            None => None,
+        };
+        let (found_collections, positions) =
+            self._select(&collection_name, maybe_query, sort, limit, skip)?;
+        if maybe_query.is_some() {
+            // Query results are not modified once found, so they are shared
+            return Ok(Cursor::new(
+                found_collections,
+                positions,
+                projection,
+                batch_size,
+            ));
+        }
+        // Without a query the result is the collection itself, which later
+        // updates change, so the selected documents are copied
+        let snapshot: Vec<Value> = {
+            let collection = found_collections.read().unwrap();
+            positions.iter().map(|i| collection[*i].clone()).collect()
+        };
+        let positions = (0..snapshot.len()).collect();
+        Ok(Cursor::new(
+            Arc::new(RwLock::new(snapshot)),
+            positions,
+            projection,
+            batch_size,
+        ))
+    }
+
+    #[pyo3(signature = (collection_name, maybe_query = None, projection = None))]
+    pub fn find_one(
+        &mut self,
+        collection_name: String,
+        maybe_query: Option<&Bound<'_, PyDict>>,
+        projection: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        // First document matching the query, or None
+        let projection = match projection {
+            Some(p) => Some(Projection::new(depythonize_query(p)?.as_object().unwrap())?),
+            None => None,
+        };
+        let query_engine = compile_filter(maybe_query)?;
+        let collection_arc = self._collection(&collection_name)?;
+        let collection = collection_arc.read().unwrap();
+        for document in collection.iter() {
+            if matches_filter(query_engine.as_ref(), document)? {
+                return Python::with_gil(|py| match &projection {
+                    Some(projection) => to_python(py, &projection.apply(document)),
+                    None => to_python(py, document),
+                });
+            }
+        }
+        Ok(Python::with_gil(|py| py.None()))
+    }
+
+    #[pyo3(signature = (collection_name, maybe_query = None))]
+    pub fn count(
+        &mut self,
+        collection_name: String,
+        maybe_query: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<usize> {
+        let query_engine = compile_filter(maybe_query)?;
+        let collection_arc = self._collection(&collection_name)?;
+        let collection = collection_arc.read().unwrap();
+        let mut count = 0;
+        for document in collection.iter() {
+            if matches_filter(query_engine.as_ref(), document)? {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    #[pyo3(signature = (collection_name, field, maybe_query = None))]
+    pub fn distinct(
+        &mut self,
+        collection_name: String,
+        field: String,
+        maybe_query: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        // Unique values of a field in the documents matching the query, in the
+        // order they are found. The elements of arrays are values on their own,
+        // and numbers that compare equal like 1 and 1.0 are the same value
+        let query_engine = compile_filter(maybe_query)?;
+        let fields: Vec<String> = field.split('.').map(|k| k.to_string()).collect();
+        let collection_arc = self._collection(&collection_name)?;
+        let collection = collection_arc.read().unwrap();
+        let mut seen: HashSet<Value> = HashSet::new();
+        let mut values: Vec<&Value> = Vec::new();
+        for document in collection.iter() {
+            if !matches_filter(query_engine.as_ref(), document)? {
+                continue;
+            }
+            let mut found_values: Vec<&Value> = Vec::new();
+            query::resolve_path(document, &fields, &mut found_values);
+            for value in found_values {
+                let elements = match value {
+                    Value::Array(elements) => elements.iter().collect(),
+                    _ => vec![value],
+                };
+                for element in elements {
+                    if seen.insert(query::equality_key(element)) {
+                        values.push(element);
+                    }
+                }
+            }
+        }
+        Python::with_gil(|py| collection_to_python(py, values))
+    }
+
+    pub fn aggregate(
+        &mut self,
+        collection_name: String,
+        pipeline: &Bound<'_, PyList>,
+    ) -> PyResult<PyObject> {
+        let pipeline = match depythonize_ordered(pipeline)? {
+            Value::Array(stages) => Pipeline::new(&stages)?,
+            _ => unreachable!("a list is depythonized to an array"),
+        };
+        let collection_arc = self._collection(&collection_name)?;
+        let documents = pipeline.execute(&collection_arc.read().unwrap())?;
+        Python::with_gil(|py| collection_to_python(py, &documents))
+    }
+
+    #[pyo3(signature = (collection_name, update_query, maybe_query = None, return_result=false, upsert=false, array_filters = None))]
     pub fn update(
         &mut self,
         collection_name: String,
         update_query: &Bound<'_, PyDict>,
         maybe_query: Option<&Bound<'_, PyDict>>,
         return_result: bool,
+        upsert: bool,
+        array_filters: Option<&Bound<'_, PyList>>,
     ) -> PyResult<Option<PyObject>> {
         // Reset cache after every update
         self.query_cache = LruCache::new(query::QUERY_CACHE_SIZE);
 
-        let updated_collections = self
-            ._update(&collection_name, update_query, maybe_query)
-            .unwrap();
+        let updated_collections = self._update(
+            &collection_name,
+            update_query,
+            maybe_query,
+            upsert,
+            array_filters,
+        )?;
 
         let return_value = match return_result {
             true => {
@@ -427,13 +777,9 @@ impl Bison {
                     let mut py_error: Option<PyErr> = None;
 
                     Python::with_gil(|py| {
-                        match pythonize(py, updated_collections.as_ref()) {
-                            Ok(obj) => {
-                                // Convert &PyAny to PyObject
-                                let py_obj = obj.to_object(py);
-                                result = Some(py_obj);
-                            }
-                            Err(err) => py_error = Some(err.into()),
+                        match collection_to_python(py, updated_collections.read().unwrap().iter()) {
+                            Ok(obj) => result = Some(obj),
+                            Err(err) => py_error = Some(err),
                         }
                     });
 
@@ -446,11 +792,80 @@ impl Bison {
             }
             false => Option::None,
         };
+        if upsert {
+            self.id_index.remove(&collection_name);
+        }
         self.collections
             .insert(collection_name, updated_collections);
         Ok(return_value)
     }
 
+    pub fn find_by_id(
+        &mut self,
+        collection_name: String,
+        id: &Bound<'_, PyAny>,
+    ) -> PyResult<PyObject> {
+        let id: Value = depythonize_query(id)?;
+        let position = self.id_positions(&collection_name)?.get(&id).copied();
+        let collection_arc = self.collections.get(&collection_name).unwrap();
+        let collection = collection_arc.read().unwrap();
+        Python::with_gil(|py| match position {
+            Some(i) => to_python(py, &collection[i]),
+            None => Ok(py.None()),
+        })
+    }
+
+    pub fn update_by_id(
+        &mut self,
+        collection_name: String,
+        id: &Bound<'_, PyAny>,
+        update_query: &Bound<'_, PyDict>,
+    ) -> PyResult<PyObject> {
+        // Returns the updated document, or None if no document has this _id
+        self.query_cache = LruCache::new(query::QUERY_CACHE_SIZE);
+        let id: Value = depythonize_query(id)?;
+        let update_query: Value = depythonize_ordered(update_query)?;
+        let update_query_engine =
+            query::QueryEngine::<UpdateOperator>::new(update_query.as_object().unwrap())?;
+        let array_filters = query::ArrayFilters::new(&[], &update_query_engine)?;
+        let position = self.id_positions(&collection_name)?.get(&id).copied();
+        let collection_arc = self.collections.get(&collection_name).unwrap();
+        let mut collection = collection_arc.write().unwrap();
+        let updated = match position {
+            Some(i) => {
+                let mut updated = collection[i].clone();
+                update_query_engine
+                    .execute(updated.as_object_mut().unwrap(), None, &array_filters)
+                    .map_err(|failure| failure.into_py_err(&collection[i]))?;
+                collection[i] = updated.clone();
+                updated
+            }
+            None => Value::Null,
+        };
+        Python::with_gil(|py| to_python(py, &updated))
+    }
+
+    pub fn delete_by_id(
+        &mut self,
+        collection_name: String,
+        id: &Bound<'_, PyAny>,
+    ) -> PyResult<usize> {
+        // Reset cache after every delete
+        self.query_cache = LruCache::new(query::QUERY_CACHE_SIZE);
+        let id: Value = depythonize_query(id)?;
+        let position = self.id_positions(&collection_name)?.get(&id).copied();
+        match position {
+            Some(i) => {
+                let collection_arc = self.collections.get(&collection_name).unwrap();
+                collection_arc.write().unwrap().remove(i);
+                // Documents after the removed one have moved
+                self.id_index.remove(&collection_name);
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
     pub fn collections(&self) -> PyResult<Vec<String>> {
         // Get collection names
         let entries = fs::read_dir(self.base_path.as_path())?
@@ -469,10 +884,33 @@ impl Bison {
         Ok(entries)
     }
 
+    pub fn delete_one(
+        &mut self,
+        collection_name: String,
+        filter_query: &Bound<'_, PyDict>,
+    ) -> PyResult<usize> {
+        // Reset cache after every delete
+        self.query_cache = LruCache::new(query::QUERY_CACHE_SIZE);
+        self.id_index.remove(&collection_name);
+        self._delete(&collection_name, filter_query, Some(1))
+    }
+
+    pub fn delete_many(
+        &mut self,
+        collection_name: String,
+        filter_query: &Bound<'_, PyDict>,
+    ) -> PyResult<usize> {
+        // Reset cache after every delete
+        self.query_cache = LruCache::new(query::QUERY_CACHE_SIZE);
+        self.id_index.remove(&collection_name);
+        self._delete(&collection_name, filter_query, None)
+    }
+
     pub fn drop_collection(&mut self, collection_name: String) -> PyResult<()> {
         let path = self.get_collection_path(&collection_name);
         let _ = fs::remove_file(path);
         self.collections.remove_entry(&collection_name);
+        self.id_index.remove(&collection_name);
         Ok(())
     }
 
@@ -517,5 +955,145 @@ impl Bison {
 #[pymodule]
 fn bison(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Bison>()?;
+    m.add_class::<Cursor>()?;
+    m.add(
+        "InvalidQueryError",
+        m.py().get_type_bound::<InvalidQueryError>(),
+    )?;
+    m.add("UpdateError", m.py().get_type_bound::<UpdateError>())?;
     Ok(())
 }
+
+fn compile_filter(
+    maybe_query: Option<&Bound<'_, PyDict>>,
+) -> Result<Option<query::QueryEngine<QueryOperator>>, PyErr> {
+    match maybe_query {
+        Some(q) => {
+            let query: Value = depythonize_query(q)?;
+            Ok(Some(query::QueryEngine::<QueryOperator>::new(
+                query.as_object().unwrap(),
+            )?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn matches_filter(
+    query_engine: Option<&query::QueryEngine<QueryOperator>>,
+    document: &Value,
+) -> Result<bool, PyErr> {
+    match query_engine {
+        Some(engine) => engine.execute(document.as_object().unwrap()),
+        None => Ok(true),
+    }
+}
+
+fn depythonize_ordered<T>(query: &Bound<'_, T>) -> Result<Value, PyErr> {
+    // Like depythonize_query, for queries with $sort fields
+    let mut value = depythonize_query(query)?;
+    keep_sort_order(query.as_any(), &mut value)?;
+    Ok(value)
+}
+
+fn keep_sort_order(py_value: &Bound<'_, PyAny>, value: &mut Value) -> Result<(), PyErr> {
+    // Dicts lose the order of their keys, so the fields of every $sort are
+    // kept as a list of [field, direction] pairs
+    if let Ok(dict) = py_value.downcast::<PyDict>() {
+        for (key, py_item) in dict.iter() {
+            let Ok(key) = key.extract::<String>() else {
+                continue;
+            };
+            let Some(item) = value.get_mut(&key) else {
+                continue;
+            };
+            match py_item.downcast::<PyDict>() {
+                Ok(sort) if key == "$sort" => {
+                    let mut pairs = Vec::with_capacity(sort.len());
+                    for (field, direction) in sort.iter() {
+                        pairs.push(Value::Array(vec![
+                            depythonize_query(&field)?,
+                            depythonize_query(&direction)?,
+                        ]));
+                    }
+                    *item = Value::Array(pairs);
+                }
+                _ => keep_sort_order(&py_item, item)?,
+            }
+        }
+    } else if let (Ok(list), Some(elements)) = (py_value.downcast::<PyList>(), value.as_array_mut())
+    {
+        for (py_item, item) in list.iter().zip(elements) {
+            keep_sort_order(&py_item, item)?;
+        }
+    }
+    Ok(())
+}
+
+fn new_id() -> Value {
+    Value::String(Uuid::new_v4().to_string())
+}
+
+fn depythonize_query<T>(query: &Bound<'_, T>) -> Result<Value, PyErr> {
+    // Queries must be JSON-like, e.g. a set or a datetime cannot be queried
+    let mut value: Value = depythonize(query.as_any())
+        .map_err(|err| InvalidQueryError::new_err(format!("Invalid query: {}", err)))?;
+    query::normalize_numbers(&mut value);
+    Ok(value)
+}
+
+fn depythonize_document<T>(document: &Bound<'_, T>) -> Result<Value, PyErr> {
+    // Documents must be JSON-like too, e.g. a datetime cannot be stored
+    let mut value: Value = depythonize(document.as_any())
+        .map_err(|err| PyErr::new::<PyValueError, _>(format!("Invalid document: {}", err)))?;
+    query::normalize_numbers(&mut value);
+    Ok(value)
+}
+
+pub fn collection_to_python<'a>(
+    py: Python<'_>,
+    collection: impl IntoIterator<Item = &'a Value>,
+) -> PyResult<PyObject> {
+    let documents = collection
+        .into_iter()
+        .map(|document| to_python(py, document))
+        .collect::<PyResult<Vec<PyObject>>>()?;
+    Ok(PyList::new_bound(py, documents).to_object(py))
+}
+
+pub fn to_python(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    // Numbers are stored with arbitrary precision, which pythonize would
+    // return as objects, so integers of any size are converted to int here
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(b) => b.to_object(py),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.to_object(py)
+            } else if let Some(u) = n.as_u64() {
+                u.to_object(py)
+            } else if n.is_f64() {
+                n.as_f64().to_object(py)
+            } else {
+                match n.to_string().parse::<BigInt>() {
+                    Ok(big_int) => big_int.to_object(py),
+                    Err(_) => n.as_f64().to_object(py),
+                }
+            }
+        }
+        Value::String(string) => string.to_object(py),
+        Value::Array(elements) => {
+            let elements = elements
+                .iter()
+                .map(|element| to_python(py, element))
+                .collect::<PyResult<Vec<PyObject>>>()?;
+            PyList::new_bound(py, elements).to_object(py)
+        }
+        Value::Object(map) => {
+            let dict = PyDict::new_bound(py);
+            for (key, value) in map {
+                dict.set_item(key, to_python(py, value)?)?;
+            }
+            dict.to_object(py)
+        }
+    })
+}
@@ -0,0 +1,235 @@
+use crate::query::invalid_query;
+use pyo3::PyErr;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+#[derive(Debug)]
+enum FieldProjection {
+    // A field that is included or excluded as a whole
+    Field,
+    // {"$slice": n} or {"$slice": [skip, limit]}
+    Slice(i64, Option<usize>),
+    Nested(HashMap<String, FieldProjection>),
+}
+
+#[derive(Debug)]
+pub struct Projection {
+    // Inclusion projections only return the listed fields, exclusion
+    // projections return everything but the listed fields
+    include: bool,
+    // _id is returned unless it is excluded explicitly
+    include_id: bool,
+    fields: HashMap<String, FieldProjection>,
+}
+
+impl Projection {
+    pub fn new(projection: &Map<String, Value>) -> Result<Self, PyErr> {
+        Projection::parse(projection, false)
+    }
+
+    pub fn inclusion(projection: &Map<String, Value>) -> Result<Self, PyErr> {
+        // Projection that only returns the listed fields, even if there are none
+        Projection::parse(projection, true)
+    }
+
+    fn parse(projection: &Map<String, Value>, inclusion: bool) -> Result<Self, PyErr> {
+        let mut include: Option<bool> = None;
+        let mut id: Option<bool> = None;
+        let mut fields = HashMap::new();
+        for (key, value) in projection {
+            let path: Vec<String> = key.split('.').map(|s| s.to_string()).collect();
+            let field = match value {
+                Value::Object(operator) => parse_slice(&path, operator)?,
+                _ => {
+                    let included = match value {
+                        Value::Bool(b) => *b,
+                        Value::Number(n) if n.as_f64().is_some() => n.as_f64() != Some(0.0),
+                        _ => {
+                            return Err(invalid_query(
+                                &path,
+                                "projection values must be 0, 1 or {\"$slice\": ...}",
+                            ))
+                        }
+                    };
+                    if key == "_id" {
+                        id = Some(included);
+                        continue;
+                    }
+                    if include.is_some_and(|include| include != included)
+                        || (inclusion && !included)
+                    {
+                        return Err(invalid_query(
+                            &path,
+                            "cannot mix inclusion and exclusion in a projection",
+                        ));
+                    }
+                    include = Some(included);
+                    FieldProjection::Field
+                }
+            };
+            insert_path(&mut fields, &path, field)?;
+        }
+        // A projection of only {"_id": 1} returns only the ids
+        let include = include.unwrap_or(inclusion || (id == Some(true) && fields.is_empty()));
+        Ok(Projection {
+            include,
+            include_id: id != Some(false),
+            fields,
+        })
+    }
+
+    pub fn apply(&self, document: &Value) -> Value {
+        let Value::Object(obj) = document else {
+            return document.clone();
+        };
+        let mut projected = match self.include {
+            true => include_fields(obj, &self.fields),
+            false => exclude_fields(obj, &self.fields),
+        };
+        match (self.include_id, obj.get("_id")) {
+            (true, Some(id)) => {
+                projected.insert("_id".to_string(), id.clone());
+            }
+            (false, _) => {
+                projected.remove("_id");
+            }
+            _ => {}
+        }
+        Value::Object(projected)
+    }
+}
+
+fn parse_slice(path: &[String], operator: &Map<String, Value>) -> Result<FieldProjection, PyErr> {
+    let slice = match operator.get("$slice") {
+        Some(slice) if operator.len() == 1 => slice,
+        _ => {
+            return Err(invalid_query(
+                path,
+                "the only projection operator supported is $slice",
+            ))
+        }
+    };
+    match slice {
+        Value::Number(n) if n.as_i64().is_some() => {
+            Ok(FieldProjection::Slice(n.as_i64().unwrap(), None))
+        }
+        Value::Array(arguments) => match arguments.as_slice() {
+            [skip, limit] if skip.is_i64() && limit.as_u64().is_some_and(|limit| limit > 0) => {
+                Ok(FieldProjection::Slice(
+                    skip.as_i64().unwrap(),
+                    Some(limit.as_u64().unwrap() as usize),
+                ))
+            }
+            _ => Err(invalid_query(
+                path,
+                "$slice takes [skip, limit] with a positive limit",
+            )),
+        },
+        _ => Err(invalid_query(
+            path,
+            "$slice takes an integer or [skip, limit]",
+        )),
+    }
+}
+
+fn insert_path(
+    fields: &mut HashMap<String, FieldProjection>,
+    path: &[String],
+    field: FieldProjection,
+) -> Result<(), PyErr> {
+    let (key, rest) = path.split_first().unwrap();
+    if rest.is_empty() {
+        if fields.insert(key.clone(), field).is_some() {
+            return Err(invalid_query(path, "path collision in projection"));
+        }
+        return Ok(());
+    }
+    let nested = fields
+        .entry(key.clone())
+        .or_insert_with(|| FieldProjection::Nested(HashMap::new()));
+    match nested {
+        FieldProjection::Nested(nested) => insert_path(nested, rest, field),
+        _ => Err(invalid_query(path, "path collision in projection")),
+    }
+}
+
+fn include_fields(
+    obj: &Map<String, Value>,
+    fields: &HashMap<String, FieldProjection>,
+) -> Map<String, Value> {
+    let mut projected = Map::new();
+    for (key, field) in fields {
+        let Some(value) = obj.get(key) else {
+            continue;
+        };
+        let value = match field {
+            FieldProjection::Field => value.clone(),
+            FieldProjection::Slice(skip, limit) => slice(value, *skip, *limit),
+            FieldProjection::Nested(nested) => match value {
+                Value::Object(obj) => Value::Object(include_fields(obj, nested)),
+                // Only the objects in an array have the nested fields
+                Value::Array(elements) => Value::Array(
+                    elements
+                        .iter()
+                        .filter_map(|element| element.as_object())
+                        .map(|element| Value::Object(include_fields(element, nested)))
+                        .collect(),
+                ),
+                _ => continue,
+            },
+        };
+        projected.insert(key.clone(), value);
+    }
+    projected
+}
+
+fn exclude_fields(
+    obj: &Map<String, Value>,
+    fields: &HashMap<String, FieldProjection>,
+) -> Map<String, Value> {
+    let mut projected = Map::new();
+    for (key, value) in obj {
+        let value = match fields.get(key) {
+            None => value.clone(),
+            Some(FieldProjection::Field) => continue,
+            Some(FieldProjection::Slice(skip, limit)) => slice(value, *skip, *limit),
+            Some(FieldProjection::Nested(nested)) => exclude_nested(value, nested),
+        };
+        projected.insert(key.clone(), value);
+    }
+    projected
+}
+
+fn exclude_nested(value: &Value, fields: &HashMap<String, FieldProjection>) -> Value {
+    match value {
+        Value::Object(obj) => Value::Object(exclude_fields(obj, fields)),
+        Value::Array(elements) => Value::Array(
+            elements
+                .iter()
+                .map(|element| exclude_nested(element, fields))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+fn slice(value: &Value, skip: i64, limit: Option<usize>) -> Value {
+    let Value::Array(elements) = value else {
+        return value.clone();
+    };
+    let len = elements.len() as i64;
+    let (start, end) = match limit {
+        // {"$slice": n} keeps the first n elements, or the last n if negative
+        None if skip >= 0 => (0, skip.min(len)),
+        None => ((len + skip).max(0), len),
+        Some(limit) => {
+            let start = match skip >= 0 {
+                true => skip.min(len),
+                false => (len + skip).max(0),
+            };
+            let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+            (start, start.saturating_add(limit).min(len))
+        }
+    };
+    Value::Array(elements[start as usize..end as usize].to_vec())
+}
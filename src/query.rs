@@ -1,11 +1,36 @@
+use chrono::{SecondsFormat, Utc};
+use num_bigint::BigInt;
 use pyo3::PyErr;
+use regex::Regex;
 use serde_json::{Map, Number, Value};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::num::NonZeroUsize;
 use std::str::FromStr;
 
+#[allow(clippy::useless_nonzero_new_unchecked)]
 pub const QUERY_CACHE_SIZE: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(100) };
 
+// pyo3 0.22 checks for its own gil-refs feature inside the macro
+#[allow(unexpected_cfgs)]
+mod exceptions {
+    pyo3::create_exception!(
+        bison,
+        InvalidQueryError,
+        pyo3::exceptions::PyValueError,
+        "Raised when a query or update cannot be compiled."
+    );
+    pyo3::create_exception!(
+        bison,
+        UpdateError,
+        pyo3::exceptions::PyValueError,
+        "Raised when an update cannot be applied to a document."
+    );
+}
+pub use exceptions::{InvalidQueryError, UpdateError};
+
 #[derive(Debug)]
 pub enum QueryOperator {
     Equal,
@@ -14,9 +39,40 @@ pub enum QueryOperator {
     GreaterThanEqual,
     LessThan,
     LessThanEqual,
+    In,
+    NotIn,
+    Exists,
+    Type,
+    StartsWith,
+    EndsWith,
+    Contains,
+    // The regex is compiled once when the query is parsed
+    Regex(Regex),
+    Size,
+    All,
+    ElemMatch(Box<QueryEngine<QueryOperator>>),
+    // Logical operators hold the compiled sub-queries they combine
+    And(Vec<QueryEngine<QueryOperator>>),
+    Or(Vec<QueryEngine<QueryOperator>>),
+    Nor(Vec<QueryEngine<QueryOperator>>),
+    Not(Box<QueryEngine<QueryOperator>>),
+    // $expr evaluates an expression against the whole collection
+    Expr(Box<Expression>),
+}
+
+#[derive(Debug)]
+pub enum Expression {
+    // A field reference like "$a.b"
+    Field(Vec<String>),
+    Literal(Value),
+    Add(Vec<Expression>),
+    Subtract(Vec<Expression>),
+    Multiply(Vec<Expression>),
+    // Only comparison operators ($eq, $ne, $gt, $gte, $lt, $lte)
+    Compare(QueryOperator, Vec<Expression>),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum UpdateOperator {
     Set,
     Add,
@@ -24,6 +80,46 @@ pub enum UpdateOperator {
     Increment,
     Decrement,
     Delete,
+    // Array operators, the values to add are always stored as an array
+    Push(PushModifiers),
+    AddToSet,
+    Pop,
+    Pull(Box<QueryEngine<QueryOperator>>),
+    // Only applied when an upsert inserts a new document
+    SetOnInsert,
+    // Path the field is moved to
+    Rename(Vec<String>),
+    Min,
+    Max,
+    Multiply,
+    CurrentDate,
+}
+
+#[derive(Debug)]
+pub struct ArrayFilters {
+    // Queries of every $[identifier], e.g. {"item.qty": {"$gt": 1}} for $[item]
+    filters: HashMap<String, QueryEngine<QueryOperator>>,
+}
+
+#[derive(Debug)]
+pub struct UpdateFailure {
+    // An update that could not be applied to a document
+    path: String,
+    reason: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+}
+
+#[derive(Debug, Default)]
+pub struct PushModifiers {
+    // {"$push": {"$each": [...], "$sort": 1, "$slice": -5}}
+    sort: Option<Value>,
+    slice: Option<i64>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -49,6 +145,15 @@ impl FromStr for QueryOperator {
             "$gte" => Ok(QueryOperator::GreaterThanEqual),
             "$lt" => Ok(QueryOperator::LessThan),
             "$lte" => Ok(QueryOperator::LessThanEqual),
+            "$in" => Ok(QueryOperator::In),
+            "$nin" => Ok(QueryOperator::NotIn),
+            "$exists" => Ok(QueryOperator::Exists),
+            "$type" => Ok(QueryOperator::Type),
+            "$startsWith" => Ok(QueryOperator::StartsWith),
+            "$endsWith" => Ok(QueryOperator::EndsWith),
+            "$contains" => Ok(QueryOperator::Contains),
+            "$size" => Ok(QueryOperator::Size),
+            "$all" => Ok(QueryOperator::All),
             _ => Err(()),
         }
     }
@@ -65,160 +170,685 @@ impl FromStr for UpdateOperator {
             "$inc" => Ok(UpdateOperator::Increment),
             "$dec" => Ok(UpdateOperator::Decrement),
             "$delete" => Ok(UpdateOperator::Delete),
+            "$addToSet" => Ok(UpdateOperator::AddToSet),
+            "$pop" => Ok(UpdateOperator::Pop),
+            "$setOnInsert" => Ok(UpdateOperator::SetOnInsert),
+            "$min" => Ok(UpdateOperator::Min),
+            "$max" => Ok(UpdateOperator::Max),
+            "$mul" => Ok(UpdateOperator::Multiply),
+            "$currentDate" => Ok(UpdateOperator::CurrentDate),
             _ => Err(()),
         }
     }
 }
 
+impl UpdateOperator {
+    fn name(&self) -> &'static str {
+        match self {
+            UpdateOperator::Set => "$set",
+            UpdateOperator::Add => "$add",
+            UpdateOperator::Substract => "$substract",
+            UpdateOperator::Increment => "$inc",
+            UpdateOperator::Decrement => "$dec",
+            UpdateOperator::Delete => "$delete",
+            UpdateOperator::Push(_) => "$push",
+            UpdateOperator::AddToSet => "$addToSet",
+            UpdateOperator::Pop => "$pop",
+            UpdateOperator::Pull(_) => "$pull",
+            UpdateOperator::SetOnInsert => "$setOnInsert",
+            UpdateOperator::Rename(_) => "$rename",
+            UpdateOperator::Min => "$min",
+            UpdateOperator::Max => "$max",
+            UpdateOperator::Multiply => "$mul",
+            UpdateOperator::CurrentDate => "$currentDate",
+        }
+    }
+}
+
+impl UpdateFailure {
+    pub fn into_py_err(self, document: &Value) -> PyErr {
+        UpdateError::new_err(format!(
+            "Cannot update '{}' in document {}: {}",
+            self.path, document, self.reason
+        ))
+    }
+}
+
 impl Query<QueryOperator> {
     pub fn execute(&self, collection: &Map<String, Value>) -> Result<bool, PyErr> {
-        let mut current_value = collection;
-
-        for key in &self.fields[..self.fields.len() - 1] {
-            match current_value.get(key) {
-                Some(Value::Object(map)) => {
-                    current_value = map;
+        // Logical operators are evaluated against the whole collection
+        if let Some(result) = self._execute_logical(|engine| engine.execute(collection)) {
+            return result;
+        }
+        if let QueryOperator::Expr(expression) = &self.operator {
+            return Ok(expression.evaluate(collection)? == Value::Bool(true));
+        }
+        if self.fields.is_empty() {
+            return Err(InvalidQueryError::new_err(format!(
+                "Invalid query: operator {:?} is not applied to a field",
+                self.operator
+            )));
+        }
+        // Paths through arrays can lead to several values, e.g. items.*.sku
+        let mut found_values: Vec<&Value> = Vec::new();
+        if let Some(value) = collection.get(&self.fields[0]) {
+            resolve_path(value, &self.fields[1..], &mut found_values);
+        }
+        self._execute_found(found_values)
+    }
+    pub fn execute_element(&self, depth: usize, element: &Value) -> Result<bool, PyErr> {
+        // Executes the query on an array element as if it was the value at
+        // the first depth fields, e.g. {"items.sku": "a"} on an element of items
+        let remaining_fields = &self.fields[depth..];
+        if remaining_fields.is_empty() {
+            return match &self.operator {
+                QueryOperator::ElemMatch(engine) => engine.execute_value(element),
+                _ => self._execute_value(element),
+            };
+        }
+        let mut found_values: Vec<&Value> = Vec::new();
+        resolve_path(element, remaining_fields, &mut found_values);
+        self._execute_found(found_values)
+    }
+    fn _execute_found(&self, found_values: Vec<&Value>) -> Result<bool, PyErr> {
+        if found_values.is_empty() {
+            return self._execute_missing();
+        }
+        match self.operator {
+            // Negations have to hold for every value found
+            QueryOperator::NotEqual | QueryOperator::NotIn => {
+                for value in found_values {
+                    if !self._execute_value(value)? {
+                        return Ok(false);
+                    }
                 }
-                _ => {
-                    return Ok(false);
+                Ok(true)
+            }
+            _ => {
+                for value in found_values {
+                    if self._execute_value(value)? {
+                        return Ok(true);
+                    }
                 }
+                Ok(false)
             }
         }
-        let last_key = &self.fields[self.fields.len() - 1];
-        match current_value.get(last_key) {
-            Some(value) => self._execute_operator(value),
-            None => Ok(false),
+    }
+    pub fn execute_value(&self, value: &Value) -> Result<bool, PyErr> {
+        // Executes the query against any value, e.g. an array element in $elemMatch.
+        // Queries without fields like {"$gt": 10} are applied to the value itself.
+        if let Some(result) = self._execute_logical(|engine| engine.execute_value(value)) {
+            return result;
+        }
+        match value {
+            Value::Object(map) if matches!(self.operator, QueryOperator::Expr(_)) => {
+                self.execute(map)
+            }
+            _ if matches!(self.operator, QueryOperator::Expr(_)) => Ok(false),
+            _ if self.fields.is_empty() => self._execute_value(value),
+            Value::Object(map) => self.execute(map),
+            _ => self._execute_missing(),
         }
     }
-    pub fn _execute_operator(&self, last_value: &Value) -> Result<bool, PyErr> {
+    fn _execute_logical<F>(&self, execute_engine: F) -> Option<Result<bool, PyErr>>
+    where
+        F: Fn(&QueryEngine<QueryOperator>) -> Result<bool, PyErr>,
+    {
+        let result = match &self.operator {
+            QueryOperator::And(engines) => engines
+                .iter()
+                .try_fold(true, |found, engine| Ok(found && execute_engine(engine)?)),
+            QueryOperator::Or(engines) => engines
+                .iter()
+                .try_fold(false, |found, engine| Ok(found || execute_engine(engine)?)),
+            QueryOperator::Nor(engines) => engines
+                .iter()
+                .try_fold(true, |found, engine| Ok(found && !execute_engine(engine)?)),
+            QueryOperator::Not(engine) => execute_engine(engine).map(|found| !found),
+            _ => return None,
+        };
+        Some(result)
+    }
+    pub fn _execute_value(&self, value: &Value) -> Result<bool, PyErr> {
+        // Operators on array fields are also applied to every element,
+        // so {"tags": "rust"} matches {"tags": ["rust", "db"]}
+        let Value::Array(elements) = value else {
+            return self._execute_operator(value);
+        };
         match self.operator {
-            QueryOperator::Equal => Ok(&self.value == last_value),
-            QueryOperator::NotEqual => Ok(&self.value != last_value),
-            // TODO: The following should panic, an a comprehensible error
-            // be sent to python
-            QueryOperator::GreaterThan => {
-                if let (Some(query_value), Some(found_value)) =
-                    (self.value.as_number(), last_value.as_number())
-                {
-                    return Ok(found_value.clone().as_f64() > query_value.clone().as_f64());
+            // Operators that compare the whole value, including arrays
+            QueryOperator::Equal
+            | QueryOperator::In
+            | QueryOperator::Type
+            | QueryOperator::GreaterThan
+            | QueryOperator::GreaterThanEqual
+            | QueryOperator::LessThan
+            | QueryOperator::LessThanEqual => {
+                if self._execute_operator(value)? {
+                    return Ok(true);
                 }
-                Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
-                    "Malformed query, non-numeric value found for operator {:?}",
-                    self.operator
-                )))
-            }
-
-            QueryOperator::LessThan => {
-                if let (Some(query_value), Some(found_value)) =
-                    (self.value.as_number(), last_value.as_number())
-                {
-                    return Ok(found_value.clone().as_f64() < query_value.clone().as_f64());
+                for element in elements {
+                    if self._execute_operator(element)? {
+                        return Ok(true);
+                    }
                 }
-
-                Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
-                    "Malformed query, non-numeric value found for operator {:?}",
-                    self.operator
-                )))
+                Ok(false)
             }
-
-            QueryOperator::GreaterThanEqual => {
-                if let (Some(query_value), Some(found_value)) =
-                    (self.value.as_number(), last_value.as_number())
-                {
-                    return Ok(found_value.clone().as_f64() >= query_value.clone().as_f64());
+            // Negations only match when no element is excluded
+            QueryOperator::NotEqual | QueryOperator::NotIn => {
+                if !self._execute_operator(value)? {
+                    return Ok(false);
                 }
-
-                Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
-                    "Malformed query, non-numeric value found for operator {:?}",
-                    self.operator
-                )))
+                for element in elements {
+                    if !self._execute_operator(element)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
             }
-            QueryOperator::LessThanEqual => {
-                if let (Some(query_value), Some(found_value)) =
-                    (self.value.as_number(), last_value.as_number())
-                {
-                    return Ok(found_value.clone().as_f64() <= query_value.clone().as_f64());
+            // Operators that are only defined on the elements
+            QueryOperator::StartsWith
+            | QueryOperator::EndsWith
+            | QueryOperator::Contains
+            | QueryOperator::Regex(_) => {
+                for element in elements {
+                    if self._execute_operator(element)? {
+                        return Ok(true);
+                    }
                 }
-
-                Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
-                    "Malformed query, non-numeric value found for operator {:?}",
-                    self.operator
-                )))
+                Ok(false)
             }
+            _ => self._execute_operator(value),
+        }
+    }
+    pub fn _execute_missing(&self) -> Result<bool, PyErr> {
+        // A missing field compares like null, so {"a": null} matches
+        // documents without "a" and {"a": {"$ne": null}} requires it
+        match self.operator {
+            QueryOperator::Equal
+            | QueryOperator::NotEqual
+            | QueryOperator::In
+            | QueryOperator::NotIn
+            | QueryOperator::GreaterThanEqual
+            | QueryOperator::LessThanEqual => self._execute_operator(&Value::Null),
+            QueryOperator::Exists => Ok(self.value == Value::Bool(false)),
+            _ => Ok(false),
+        }
+    }
+    pub fn _execute_operator(&self, last_value: &Value) -> Result<bool, PyErr> {
+        match &self.operator {
+            QueryOperator::Equal
+            | QueryOperator::NotEqual
+            | QueryOperator::GreaterThan
+            | QueryOperator::GreaterThanEqual
+            | QueryOperator::LessThan
+            | QueryOperator::LessThanEqual => Ok(compare_with_operator(
+                &self.operator,
+                last_value,
+                &self.value,
+            )),
+            // Operands are validated when the query is parsed, see parse_field_query
+            QueryOperator::In | QueryOperator::NotIn => {
+                let found = self.value.as_array().is_some_and(|query_values| {
+                    query_values
+                        .iter()
+                        .any(|query_value| compare_values(query_value, last_value).is_eq())
+                });
+                Ok(found == matches!(self.operator, QueryOperator::In))
+            }
+            QueryOperator::Exists => Ok(self.value == Value::Bool(true)),
+            QueryOperator::Type => Ok(self.value.as_str() == Some(value_type(last_value))),
+            QueryOperator::StartsWith | QueryOperator::EndsWith | QueryOperator::Contains => {
+                let (Some(query_value), Some(found_value)) =
+                    (self.value.as_str(), last_value.as_str())
+                else {
+                    return Ok(false);
+                };
+                Ok(match self.operator {
+                    QueryOperator::StartsWith => found_value.starts_with(query_value),
+                    QueryOperator::EndsWith => found_value.ends_with(query_value),
+                    _ => found_value.contains(query_value),
+                })
+            }
+            QueryOperator::Regex(regex) => match last_value.as_str() {
+                Some(found_value) => Ok(regex.is_match(found_value)),
+                None => Ok(false),
+            },
+            QueryOperator::Size => Ok(last_value
+                .as_array()
+                .is_some_and(|found_value| Some(found_value.len() as u64) == self.value.as_u64())),
+            QueryOperator::All => {
+                let Some(query_values) = self.value.as_array() else {
+                    return Ok(false);
+                };
+                let found_values = last_value.as_array();
+                Ok(!query_values.is_empty()
+                    && query_values.iter().all(|query_value| {
+                        compare_values(query_value, last_value).is_eq()
+                            || found_values.is_some_and(|found| {
+                                found
+                                    .iter()
+                                    .any(|value| compare_values(query_value, value).is_eq())
+                            })
+                    }))
+            }
+            QueryOperator::ElemMatch(engine) => match last_value.as_array() {
+                Some(elements) => {
+                    for element in elements {
+                        if engine.execute_value(element)? {
+                            return Ok(true);
+                        }
+                    }
+                    Ok(false)
+                }
+                None => Ok(false),
+            },
+            // Logical operators are handled in Query::execute
+            QueryOperator::And(_)
+            | QueryOperator::Or(_)
+            | QueryOperator::Nor(_)
+            | QueryOperator::Not(_)
+            | QueryOperator::Expr(_) => Ok(false),
         }
     }
 }
 
 impl Query<UpdateOperator> {
-    pub fn execute(&self, collection: &mut Map<String, Value>) -> bool {
-        let mut current_value = collection;
-
-        for key in &self.fields[..self.fields.len() - 1] {
-            match current_value.get_mut(key) {
-                Some(Value::Object(map)) => {
-                    current_value = map;
+    pub fn execute(
+        &self,
+        collection: &mut Map<String, Value>,
+        fields: &[String],
+        array_filters: &ArrayFilters,
+    ) -> Result<(), UpdateFailure> {
+        // fields are self.fields with the positional operator $ resolved
+        if let UpdateOperator::Rename(target_fields) = &self.operator {
+            // Moves the value by deleting it and setting it at the new path
+            let Some(value) = take_path(collection, &self.fields) else {
+                return Ok(());
+            };
+            return Query {
+                fields: target_fields.clone(),
+                value,
+                operator: UpdateOperator::Set,
+            }
+            .execute(collection, target_fields, array_filters);
+        }
+        self._execute_path(collection, fields, array_filters)
+    }
+    fn _execute_path(
+        &self,
+        current_value: &mut Map<String, Value>,
+        fields: &[String],
+        array_filters: &ArrayFilters,
+    ) -> Result<(), UpdateFailure> {
+        let (key, remaining_fields) = fields.split_first().unwrap();
+        // handle delete operator
+        if remaining_fields.is_empty() && matches!(self.operator, UpdateOperator::Delete) {
+            let _ = current_value.remove_entry(key);
+            return Ok(());
+        }
+        match current_value.get_mut(key) {
+            Some(value) => self._execute_element(value, remaining_fields, array_filters),
+            // Missing fields and intermediate objects are created,
+            // e.g. {"stats.views": {"$inc": ""}} on {} gives {"stats": {"views": 1}}
+            None if !is_all_elements(key) => {
+                if let Some(value) = self._create_value(remaining_fields, array_filters)? {
+                    current_value.insert(key.to_string(), value);
                 }
-                _ => {
-                    return false;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+    fn _execute_nested(
+        &self,
+        current_value: &mut Value,
+        fields: &[String],
+        array_filters: &ArrayFilters,
+    ) -> Result<(), UpdateFailure> {
+        // Follows the remaining fields through objects and arrays. Array
+        // segments are an index (items.0), every element (items.* or items.$[])
+        // or the elements matching an array filter (items.$[item])
+        match current_value {
+            Value::Object(map) => self._execute_path(map, fields, array_filters),
+            Value::Array(elements) => {
+                let (key, remaining_fields) = fields.split_first().unwrap();
+                let is_delete =
+                    remaining_fields.is_empty() && matches!(self.operator, UpdateOperator::Delete);
+                if is_all_elements(key) {
+                    let identifier = array_filter_identifier(key);
+                    if is_delete {
+                        match identifier {
+                            Some(identifier) => elements
+                                .retain(|element| !array_filters.matches(identifier, element)),
+                            None => elements.clear(),
+                        }
+                        return Ok(());
+                    }
+                    for element in elements.iter_mut() {
+                        if identifier
+                            .is_none_or(|identifier| array_filters.matches(identifier, element))
+                        {
+                            self._execute_element(element, remaining_fields, array_filters)?;
+                        }
+                    }
+                } else if let Ok(index) = key.parse::<usize>() {
+                    if index >= elements.len() {
+                        // Like missing fields, the array is padded with nulls
+                        if let Some(value) = self._create_value(remaining_fields, array_filters)? {
+                            elements.resize(index, Value::Null);
+                            elements.push(value);
+                        }
+                        return Ok(());
+                    }
+                    if is_delete {
+                        elements.remove(index);
+                        return Ok(());
+                    }
+                    self._execute_element(&mut elements[index], remaining_fields, array_filters)?;
+                } else {
+                    return self._path_error(fields, current_value);
                 }
+                Ok(())
             }
+            _ => self._path_error(fields, current_value),
         }
-        let last_key = &self.fields[self.fields.len() - 1];
-        // handle delete operator
-        if self.operator == UpdateOperator::Delete {
-            let _ = current_value.remove_entry(last_key);
-        } else if let Some(value) = current_value.get_mut(last_key) {
-            self._execute_operator(value)
+    }
+    fn _execute_element(
+        &self,
+        element: &mut Value,
+        fields: &[String],
+        array_filters: &ArrayFilters,
+    ) -> Result<(), UpdateFailure> {
+        if fields.is_empty() {
+            self._execute_operator(element)
+        } else {
+            self._execute_nested(element, fields, array_filters)
         }
-        false
     }
-    pub fn _execute_operator(&self, last_value: &mut Value) {
+    fn _resolve_positional(
+        &self,
+        collection: &Map<String, Value>,
+        filter_query_engine: Option<&QueryEngine<QueryOperator>>,
+    ) -> Result<Cow<'_, [String]>, UpdateFailure> {
+        // Replaces the positional operator, like in items.$.qty, with the
+        // index of the first element of items matched by the filter
+        let Some(position) = self.fields.iter().position(|key| key == "$") else {
+            return Ok(Cow::Borrowed(&self.fields));
+        };
+        let Some(index) = filter_query_engine
+            .and_then(|engine| engine.positional_index(collection, &self.fields[..position]))
+        else {
+            return Err(self._failure(format!(
+                "the positional operator $ did not find a match for '{}' in the filter",
+                self.fields[..position].join(".")
+            )));
+        };
+        let mut fields = self.fields.clone();
+        fields[position] = index.to_string();
+        Ok(Cow::Owned(fields))
+    }
+    fn _initial_value(&self) -> Option<Value> {
+        // Value a missing field starts from before the operator is applied.
+        // Operators that only remove values do not create fields.
         match self.operator {
+            UpdateOperator::Set | UpdateOperator::CurrentDate => Some(Value::Null),
+            // Missing fields are set to the operand
+            UpdateOperator::Min | UpdateOperator::Max => Some(self.value.clone()),
+            UpdateOperator::Add
+            | UpdateOperator::Substract
+            | UpdateOperator::Increment
+            | UpdateOperator::Decrement
+            | UpdateOperator::Multiply => Some(Value::from(0)),
+            UpdateOperator::Push(_) | UpdateOperator::AddToSet => Some(Value::Array(Vec::new())),
+            _ => None,
+        }
+    }
+    fn _create_value(
+        &self,
+        fields: &[String],
+        array_filters: &ArrayFilters,
+    ) -> Result<Option<Value>, UpdateFailure> {
+        // Value for a missing path, with the intermediate objects it needs
+        let Some(mut value) = self._initial_value() else {
+            return Ok(None);
+        };
+        if !fields.is_empty() {
+            value = Value::Object(Map::new());
+        }
+        self._execute_element(&mut value, fields, array_filters)?;
+        Ok(Some(value))
+    }
+    fn _path_error(&self, fields: &[String], found_value: &Value) -> Result<(), UpdateFailure> {
+        // The path continues through a value that cannot hold fields, like a.b on {"a": 5}
+        if self._initial_value().is_none() {
+            return Ok(());
+        }
+        let depth = self.fields.len() - fields.len();
+        Err(self._failure(format!(
+            "'{}' is {}, not an object",
+            self.fields[..depth].join("."),
+            match found_value {
+                Value::Null => "null",
+                Value::Bool(_) => "a boolean",
+                Value::Number(_) => "a number",
+                Value::String(_) => "a string",
+                _ => "an array",
+            }
+        )))
+    }
+    fn _execute_arithmetic(
+        &self,
+        last_value: &mut Value,
+        operator: Arithmetic,
+        query_value: &Number,
+    ) -> Result<(), UpdateFailure> {
+        let Value::Number(found_value) = last_value else {
+            return Err(self._failure(format!(
+                "{} expects a number, found {}",
+                self.operator.name(),
+                last_value
+            )));
+        };
+        match arithmetic(operator, found_value, query_value) {
+            Some(result) => {
+                *last_value = Value::Number(result);
+                Ok(())
+            }
+            None => Err(self._failure(format!(
+                "{} of {} and {} is not a finite number",
+                self.operator.name(),
+                found_value,
+                query_value
+            ))),
+        }
+    }
+    fn _expect_array<'v>(
+        &self,
+        last_value: &'v mut Value,
+    ) -> Result<&'v mut Vec<Value>, UpdateFailure> {
+        match last_value {
+            Value::Array(elements) => Ok(elements),
+            _ => Err(self._failure(format!(
+                "{} expects an array, found {}",
+                self.operator.name(),
+                last_value
+            ))),
+        }
+    }
+    fn _failure(&self, reason: String) -> UpdateFailure {
+        UpdateFailure {
+            path: self.fields.join("."),
+            reason,
+        }
+    }
+    pub fn _execute_operator(&self, last_value: &mut Value) -> Result<(), UpdateFailure> {
+        match &self.operator {
             UpdateOperator::Set => *last_value = self.value.clone(),
-            UpdateOperator::Increment => {
-                if let Some(found_value) = last_value.as_number() {
-                    let mut numerical_value = found_value.clone().as_f64().unwrap();
-                    numerical_value += 1.0;
-                    *last_value = Number::from_f64(numerical_value).into()
+            // Operands are validated when the update is parsed, see parse_update_query
+            UpdateOperator::Increment | UpdateOperator::Add => {
+                if let Value::Number(query_value) = &self.value {
+                    self._execute_arithmetic(last_value, Arithmetic::Add, query_value)?
                 }
             }
-            UpdateOperator::Decrement => {
-                if let Some(found_value) = last_value.as_number() {
-                    let mut numerical_value = found_value.clone().as_f64().unwrap();
-                    numerical_value -= 1.0;
-                    *last_value = Number::from_f64(numerical_value).into()
+            UpdateOperator::Decrement | UpdateOperator::Substract => {
+                if let Value::Number(query_value) = &self.value {
+                    self._execute_arithmetic(last_value, Arithmetic::Subtract, query_value)?
                 }
             }
-            UpdateOperator::Add => {
-                if let (Some(query_value), Some(found_value)) =
-                    (self.value.as_number(), last_value.as_number())
-                {
-                    let found_value = found_value.clone().as_f64().unwrap();
-                    let query_value = query_value.clone().as_f64().unwrap();
-                    let result = found_value + query_value;
-                    *last_value = Number::from_f64(result).into();
+            UpdateOperator::Multiply => {
+                if let Value::Number(query_value) = &self.value {
+                    self._execute_arithmetic(last_value, Arithmetic::Multiply, query_value)?
                 }
             }
-
-            UpdateOperator::Substract => {
-                if let (Some(query_value), Some(found_value)) =
-                    (self.value.as_number(), last_value.as_number())
-                {
-                    let found_value = found_value.clone().as_f64().unwrap();
-                    let query_value = query_value.clone().as_f64().unwrap();
-                    let result = found_value - query_value;
-                    *last_value = Number::from_f64(result).into();
+            UpdateOperator::Min => {
+                if compare_values(&self.value, last_value) == Ordering::Less {
+                    *last_value = self.value.clone();
+                }
+            }
+            UpdateOperator::Max => {
+                if compare_values(&self.value, last_value) == Ordering::Greater {
+                    *last_value = self.value.clone();
                 }
             }
+            UpdateOperator::CurrentDate => {
+                // Dates are stored as ISO 8601 strings, timestamps as milliseconds
+                let now = Utc::now();
+                *last_value = match self.value.get("$type").and_then(Value::as_str) {
+                    Some("timestamp") => Value::from(now.timestamp_millis()),
+                    _ => Value::from(now.to_rfc3339_opts(SecondsFormat::Millis, true)),
+                };
+            }
+            // Handled by Query::execute
+            UpdateOperator::Rename(_) => {}
 
             // This operator needs to be handle at key level
             UpdateOperator::Delete => {}
+            // Handled by QueryEngine::upsert
+            UpdateOperator::SetOnInsert => {}
+            UpdateOperator::Push(modifiers) => {
+                let elements = self._expect_array(last_value)?;
+                if let Value::Array(new_elements) = &self.value {
+                    elements.extend(new_elements.iter().cloned());
+                    if let Some(sort) = &modifiers.sort {
+                        elements.sort_by(|a, b| compare_with_sort(a, b, sort));
+                    }
+                    if let Some(slice) = modifiers.slice {
+                        // A negative slice keeps the last elements
+                        let keep = slice.unsigned_abs().min(elements.len() as u64) as usize;
+                        if slice < 0 {
+                            elements.drain(..elements.len() - keep);
+                        } else {
+                            elements.truncate(keep);
+                        }
+                    }
+                }
+            }
+            UpdateOperator::AddToSet => {
+                let elements = self._expect_array(last_value)?;
+                if let Value::Array(new_elements) = &self.value {
+                    for new_element in new_elements {
+                        if !elements
+                            .iter()
+                            .any(|element| compare_values(element, new_element).is_eq())
+                        {
+                            elements.push(new_element.clone());
+                        }
+                    }
+                }
+            }
+            UpdateOperator::Pop => {
+                let elements = self._expect_array(last_value)?;
+                // 1 removes the last element and -1 the first one
+                if self.value.as_i64() == Some(-1) {
+                    if !elements.is_empty() {
+                        elements.remove(0);
+                    }
+                } else {
+                    elements.pop();
+                }
+            }
+            UpdateOperator::Pull(engine) => {
+                let elements = self._expect_array(last_value)?;
+                // Every element is checked before any is removed, so a condition
+                // that cannot be evaluated leaves the array as it was
+                let removed = elements
+                    .iter()
+                    .map(|element| engine.execute_value(element))
+                    .collect::<Result<Vec<bool>, PyErr>>()
+                    .map_err(|err| self._failure(format!("$pull condition failed: {}", err)))?;
+                let mut removed = removed.into_iter();
+                elements.retain(|_| !removed.next().unwrap());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Expression {
+    pub fn evaluate(&self, collection: &Map<String, Value>) -> Result<Value, PyErr> {
+        // Evaluates the expression for one collection. Missing fields evaluate
+        // to null, and so does arithmetic on them.
+        match self {
+            Expression::Field(fields) => {
+                let mut found_values: Vec<&Value> = Vec::new();
+                if let Some(value) = collection.get(&fields[0]) {
+                    resolve_path(value, &fields[1..], &mut found_values);
+                }
+                Ok(match found_values.as_slice() {
+                    [] => Value::Null,
+                    [value] => (*value).clone(),
+                    values => Value::Array(values.iter().map(|v| (*v).clone()).collect()),
+                })
+            }
+            Expression::Literal(value) => Ok(value.clone()),
+            Expression::Add(arguments)
+            | Expression::Subtract(arguments)
+            | Expression::Multiply(arguments) => {
+                let mut numbers: Vec<Number> = Vec::new();
+                for argument in arguments {
+                    match argument.evaluate(collection)? {
+                        Value::Number(number) => numbers.push(number),
+                        Value::Null => return Ok(Value::Null),
+                        value => {
+                            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!(
+                                "Malformed query, non-numeric value {} in $expr",
+                                value
+                            )))
+                        }
+                    }
+                }
+                let mut numbers = numbers.into_iter();
+                let Some(mut result) = numbers.next() else {
+                    return Ok(Value::Null);
+                };
+                for number in numbers {
+                    let next_result = match self {
+                        Expression::Add(_) => arithmetic(Arithmetic::Add, &result, &number),
+                        Expression::Subtract(_) => {
+                            arithmetic(Arithmetic::Subtract, &result, &number)
+                        }
+                        _ => arithmetic(Arithmetic::Multiply, &result, &number),
+                    };
+                    // Results that are not finite, like inf, evaluate to null
+                    match next_result {
+                        Some(next_result) => result = next_result,
+                        None => return Ok(Value::Null),
+                    }
+                }
+                Ok(Value::Number(result))
+            }
+            Expression::Compare(operator, arguments) => {
+                let left = arguments[0].evaluate(collection)?;
+                let right = arguments[1].evaluate(collection)?;
+                Ok(Value::Bool(compare_with_operator(operator, &left, &right)))
+            }
         }
     }
 }
 
 impl QueryEngine<QueryOperator> {
-    pub fn new(unparsed_query: &Map<String, Value>) -> Self {
+    pub fn new(unparsed_query: &Map<String, Value>) -> Result<Self, PyErr> {
         // Compile an unparsed query into a list of queries.
         //
         // # Examples
@@ -226,30 +856,122 @@ impl QueryEngine<QueryOperator> {
         // let query_engine = QueryEngine({"a": 10}) // Query to search for a = 10
         // let query_engine = QueryEngine({"a": {"eq": 10}}) // Equivalent query
         // let query_engine = QueryEgine({"a": {"b": 100}}) // Nested query
+        // let query_engine = QueryEngine({"$or": [{"a": 10}, {"b": 20}]}) // Logical query
+        // let query_engine = QueryEngine({"a": {"$not": {"$gt": 10}}}) // Negated query
         //
-        let queries: Vec<Query<QueryOperator>> = unparsed_query
-            .into_iter()
-            .map(|(key, sub_query)| {
-                let mut fields: Vec<String> = Vec::new();
-                let value = parse_query(sub_query, key, &mut fields);
-                // if no '$' operator is found, assume it is an EqualOperator
-                // For example: {"a": 10} => a == 10
-                let mut query_op = QueryOperator::Equal;
-                if fields.last().unwrap().starts_with('$') {
-                    let query_op_str = fields.pop().unwrap();
-                    // TODO: Error should be a python error
-                    query_op = QueryOperator::from_str(&query_op_str).unwrap_or_else(|_| {
-                        panic!("Unknown query operator found: {}", query_op_str)
-                    });
+        // Only logical operators and $expr are valid at the top of a query
+        if let Some(key) = unparsed_query
+            .keys()
+            .find(|key| key.starts_with('$') && !is_top_level_operator(key))
+        {
+            return Err(invalid_query(
+                &[],
+                &format!("unknown top-level operator {}", key),
+            ));
+        }
+        Self::parse(unparsed_query)
+    }
+
+    fn parse(unparsed_query: &Map<String, Value>) -> Result<Self, PyErr> {
+        // Same as QueryEngine::new, but operators are also allowed at the top,
+        // e.g. {"$gt": 10} in {"a": {"$elemMatch": {"$gt": 10}}}
+        let mut queries: Vec<Query<QueryOperator>> = Vec::new();
+        let mut field_queries: Vec<(Vec<String>, Value)> = Vec::new();
+        for (key, sub_query) in unparsed_query {
+            if matches!(key.as_str(), "$and" | "$or" | "$nor") {
+                queries.push(Query {
+                    fields: Vec::new(),
+                    value: Value::Null,
+                    operator: parse_logical_operator(key, sub_query)?,
+                });
+                continue;
+            }
+            if key == "$expr" {
+                queries.push(Query {
+                    fields: Vec::new(),
+                    value: Value::Null,
+                    operator: QueryOperator::Expr(Box::new(parse_expression(sub_query)?)),
+                });
+                continue;
+            }
+            if key == "$not" {
+                // {"$not": {"$gt": 10}} negates the whole operator object
+                queries.push(parse_field_query(
+                    vec![key.clone()],
+                    sub_query.clone(),
+                    &[],
+                )?);
+                continue;
+            }
+            field_queries.extend(parse_query(sub_query, key, &[])?);
+        }
+        // Every operator in an operator object is compiled into its own query
+        // For example: {"a": {"$gte": 10, "$lt": 20}} => a >= 10 && a < 20
+        // Operators without fields are keys of their own, like in
+        // {"$regex": "^r", "$options": "i"}, so siblings are looked up in
+        // the pairs of every key
+        for (fields, value) in &field_queries {
+            // $options is read together with the $regex it modifies
+            if fields.last().unwrap() == "$options" {
+                let field = &fields[..fields.len() - 1];
+                let has_regex = field_queries.iter().any(|(regex_fields, _)| {
+                    regex_fields.last().unwrap() == "$regex"
+                        && regex_fields[..regex_fields.len() - 1] == *field
+                });
+                if !has_regex {
+                    return Err(invalid_query(field, "$options requires a $regex"));
                 }
-                Query {
-                    fields,
-                    value,
-                    operator: query_op,
+                continue;
+            }
+            queries.push(parse_field_query(
+                fields.clone(),
+                value.clone(),
+                &field_queries,
+            )?);
+        }
+        Ok(QueryEngine { queries })
+    }
+
+    fn positional_index(
+        &self,
+        collection: &Map<String, Value>,
+        array_fields: &[String],
+    ) -> Option<usize> {
+        // Index of the first element of the array at array_fields matched by
+        // a query on it, e.g. {"items.sku": "a"} for items.$
+        let Some(Value::Array(elements)) = collection
+            .get(&array_fields[0])
+            .and_then(|value| lookup_path(value, &array_fields[1..]))
+        else {
+            return None;
+        };
+        self.queries.iter().find_map(|q| match &q.operator {
+            QueryOperator::And(engines) => engines
+                .iter()
+                .find_map(|engine| engine.positional_index(collection, array_fields)),
+            _ if q.fields.starts_with(array_fields) => elements.iter().position(|element| {
+                q.execute_element(array_fields.len(), element)
+                    .unwrap_or(false)
+            }),
+            _ => None,
+        })
+    }
+
+    fn equality_fields(&self) -> Vec<(Vec<String>, Value)> {
+        // Fields fixed by the query, like {"a": 10} or {"$and": [{"a": {"$eq": 10}}]}
+        let mut fields = Vec::new();
+        for q in &self.queries {
+            match &q.operator {
+                QueryOperator::Equal if !q.fields.is_empty() => {
+                    fields.push((q.fields.clone(), q.value.clone()))
                 }
-            })
-            .collect();
-        QueryEngine { queries }
+                QueryOperator::And(engines) => {
+                    fields.extend(engines.iter().flat_map(|engine| engine.equality_fields()))
+                }
+                _ => {}
+            }
+        }
+        fields
     }
 
     pub fn execute(&self, collection: &Map<String, Value>) -> Result<bool, PyErr> {
@@ -257,7 +979,16 @@ impl QueryEngine<QueryOperator> {
         for q in query_iter {
             let query_result = q.execute(collection)?;
             if !query_result {
-                return Ok(query_result)
+                return Ok(query_result);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn execute_value(&self, value: &Value) -> Result<bool, PyErr> {
+        for q in self.queries.iter() {
+            if !q.execute_value(value)? {
+                return Ok(false);
             }
         }
         Ok(true)
@@ -265,71 +996,904 @@ impl QueryEngine<QueryOperator> {
 }
 
 impl QueryEngine<UpdateOperator> {
-    pub fn new(unparsed_query: &Map<String, Value>) -> Self {
-        let queries: Vec<Query<UpdateOperator>> = unparsed_query
-            .into_iter()
-            .map(|(key, sub_query)| {
-                let mut fields: Vec<String> = Vec::new();
-                let value = parse_query(sub_query, key, &mut fields);
+    pub fn new(unparsed_query: &Map<String, Value>) -> Result<Self, PyErr> {
+        let mut queries: Vec<Query<UpdateOperator>> = Vec::new();
+        for (key, sub_query) in unparsed_query {
+            for (mut fields, value) in parse_query(sub_query, key, &[])? {
                 // if no '$' operator is found, assume it is an SetOperator
                 // For example: {"a": 10} => a == 10
-                let mut update_op = UpdateOperator::Set;
-                if fields.last().unwrap().starts_with('$') {
-                    let update_op_str = fields.pop().unwrap();
-                    // TODO: Error should be a python error
-                    update_op = UpdateOperator::from_str(&update_op_str).unwrap_or_else(|_| {
-                        panic!("Unknown update query operator found: {}", update_op_str)
+                let positional_count = fields.iter().filter(|key| *key == "$").count();
+                if positional_count > 1 || fields[0] == "$" {
+                    return Err(invalid_query(
+                        &fields,
+                        "the positional operator $ can only follow an array field once",
+                    ));
+                }
+                // Positional segments like items.$ or items.$[] are not operators
+                let last_key = fields.last().unwrap();
+                let is_operator =
+                    last_key.starts_with('$') && last_key != "$" && !last_key.starts_with("$[");
+                let path = &fields[..fields.len() - usize::from(is_operator)];
+                check_field_names(&fields, path)?;
+                if !is_operator {
+                    queries.push(Query {
+                        fields,
+                        value,
+                        operator: UpdateOperator::Set,
                     });
+                    continue;
                 }
-                println!("Update operator found {:?}", update_op);
-                println!("Fields: {:?}", fields);
+                let update_op_str = fields.pop().unwrap();
+                queries.push(parse_update_query(fields, value, &update_op_str)?);
+            }
+        }
+        Ok(QueryEngine { queries })
+    }
+
+    pub fn execute(
+        &self,
+        collection: &mut Map<String, Value>,
+        filter_query_engine: Option<&QueryEngine<QueryOperator>>,
+        array_filters: &ArrayFilters,
+    ) -> Result<(), UpdateFailure> {
+        let id = collection.get("_id").cloned();
+        let query_iter = self.queries.iter();
+        for q in query_iter {
+            let fields = q._resolve_positional(collection, filter_query_engine)?;
+            q.execute(collection, &fields, array_filters)?;
+        }
+        // Documents are looked up by their _id, so it cannot change
+        if collection.get("_id") != id.as_ref() {
+            return Err(UpdateFailure {
+                path: "_id".to_string(),
+                reason: "the _id of a document cannot be changed".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn upsert(
+        &self,
+        filter_query_engine: Option<&QueryEngine<QueryOperator>>,
+        array_filters: &ArrayFilters,
+    ) -> Result<Map<String, Value>, PyErr> {
+        // Builds the document inserted by an upsert: the equality parts of
+        // the filter, updated by this engine with $setOnInsert applied as $set
+        let mut document = Map::new();
+        match self._upsert(filter_query_engine, array_filters, &mut document) {
+            Ok(()) => Ok(document),
+            Err(failure) => Err(failure.into_py_err(&Value::Object(document))),
+        }
+    }
+    fn _upsert(
+        &self,
+        filter_query_engine: Option<&QueryEngine<QueryOperator>>,
+        array_filters: &ArrayFilters,
+        document: &mut Map<String, Value>,
+    ) -> Result<(), UpdateFailure> {
+        if let Some(engine) = filter_query_engine {
+            for (fields, value) in engine.equality_fields() {
                 Query {
-                    fields,
+                    fields: fields.clone(),
                     value,
-                    operator: update_op,
+                    operator: UpdateOperator::Set,
                 }
-            })
+                .execute(document, &fields, array_filters)?;
+            }
+        }
+        for q in &self.queries {
+            let fields = q._resolve_positional(document, filter_query_engine)?;
+            match q.operator {
+                UpdateOperator::SetOnInsert => Query {
+                    fields: q.fields.clone(),
+                    value: q.value.clone(),
+                    operator: UpdateOperator::Set,
+                }
+                .execute(document, &fields, array_filters)?,
+                _ => q.execute(document, &fields, array_filters)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ArrayFilters {
+    pub fn new(
+        unparsed_filters: &[Value],
+        update_query_engine: &QueryEngine<UpdateOperator>,
+    ) -> Result<Self, PyErr> {
+        // Compiles the array_filters of an update, e.g. [{"item.qty": {"$gt": 1}}],
+        // grouping the queries by the identifier they start with
+        let mut filters: HashMap<String, QueryEngine<QueryOperator>> = HashMap::new();
+        for unparsed_filter in unparsed_filters {
+            let Value::Object(map) = unparsed_filter else {
+                return Err(invalid_query(
+                    &[],
+                    &format!("array filters must be queries, found {}", unparsed_filter),
+                ));
+            };
+            for q in QueryEngine::<QueryOperator>::new(map)?.queries {
+                let Some(identifier) = q.fields.first() else {
+                    return Err(invalid_query(
+                        &[],
+                        "array filters must start with an identifier, like {\"item.qty\": 1}",
+                    ));
+                };
+                filters
+                    .entry(identifier.clone())
+                    .or_insert_with(|| QueryEngine {
+                        queries: Vec::new(),
+                    })
+                    .queries
+                    .push(q);
+            }
+        }
+        // Every $[identifier] needs a filter, and every filter has to be used
+        let used_identifiers: HashSet<&str> = update_query_engine
+            .queries
+            .iter()
+            .flat_map(|q| q.fields.iter())
+            .filter_map(|key| array_filter_identifier(key))
             .collect();
-        QueryEngine { queries }
+        for identifier in &used_identifiers {
+            if !filters.contains_key(*identifier) {
+                return Err(invalid_query(
+                    &[],
+                    &format!("no array filter found for $[{}]", identifier),
+                ));
+            }
+        }
+        if let Some(identifier) = filters
+            .keys()
+            .find(|identifier| !used_identifiers.contains(identifier.as_str()))
+        {
+            return Err(invalid_query(
+                &[],
+                &format!("array filter for {} is not used in the update", identifier),
+            ));
+        }
+        Ok(ArrayFilters { filters })
     }
 
-    pub fn execute(&self, collection: &mut Map<String, Value>) {
-        let query_iter = self.queries.iter();
-        for q in query_iter {
-            q.execute(collection);
+    fn matches(&self, identifier: &str, element: &Value) -> bool {
+        self.filters.get(identifier).is_some_and(|engine| {
+            engine
+                .queries
+                .iter()
+                .all(|q| q.execute_element(1, element).unwrap_or(false))
+        })
+    }
+}
+
+pub fn resolve_path<'a>(value: &'a Value, fields: &[String], found_values: &mut Vec<&'a Value>) {
+    /*
+     * Collects every value reached by following fields from value. Array segments
+     * are an index (items.0.sku) or a wildcard (items.*.sku). Other keys are looked
+     * up in every object of the array, so items.sku is the same as items.*.sku.
+     */
+    let Some((key, remaining_fields)) = fields.split_first() else {
+        found_values.push(value);
+        return;
+    };
+    match value {
+        Value::Object(map) => {
+            if let Some(value) = map.get(key) {
+                resolve_path(value, remaining_fields, found_values);
+            }
+        }
+        Value::Array(elements) => {
+            if key == "*" {
+                for element in elements {
+                    resolve_path(element, remaining_fields, found_values);
+                }
+            } else if let Ok(index) = key.parse::<usize>() {
+                if let Some(element) = elements.get(index) {
+                    resolve_path(element, remaining_fields, found_values);
+                }
+            } else {
+                for element in elements.iter().filter(|element| element.is_object()) {
+                    resolve_path(element, fields, found_values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn compare_with_operator(
+    operator: &QueryOperator,
+    found_value: &Value,
+    query_value: &Value,
+) -> bool {
+    // Applies a comparison operator. Ordering operators only compare values
+    // of the same type, e.g. {"$gt": "2024-01-01"} never matches numbers.
+    // Equality goes through compare_values too, so 1 equals 1.0
+    if let QueryOperator::Equal = operator {
+        return compare_values(found_value, query_value).is_eq();
+    }
+    if let QueryOperator::NotEqual = operator {
+        return compare_values(found_value, query_value).is_ne();
+    }
+    if type_order(found_value) != type_order(query_value) {
+        return false;
+    }
+    let ordering = compare_values(found_value, query_value);
+    match operator {
+        QueryOperator::GreaterThan => ordering.is_gt(),
+        QueryOperator::GreaterThanEqual => ordering.is_ge(),
+        QueryOperator::LessThan => ordering.is_lt(),
+        QueryOperator::LessThanEqual => ordering.is_le(),
+        _ => false,
+    }
+}
+
+pub fn arithmetic(operator: Arithmetic, a: &Number, b: &Number) -> Option<Number> {
+    // Integers stay integers, growing into big integers instead of
+    // overflowing. Floats are only used when one of the numbers is a float.
+    if !a.is_f64() && !b.is_f64() {
+        if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+            let result = match operator {
+                Arithmetic::Add => a.checked_add(b),
+                Arithmetic::Subtract => a.checked_sub(b),
+                Arithmetic::Multiply => a.checked_mul(b),
+            };
+            if let Some(result) = result {
+                return Some(Number::from(result));
+            }
+        }
+        let (a, b) = (to_big_int(a)?, to_big_int(b)?);
+        let result = match operator {
+            Arithmetic::Add => a + b,
+            Arithmetic::Subtract => a - b,
+            Arithmetic::Multiply => a * b,
+        };
+        return Some(big_int_to_number(result));
+    }
+    let (a, b) = (a.as_f64()?, b.as_f64()?);
+    let result = match operator {
+        Arithmetic::Add => a + b,
+        Arithmetic::Subtract => a - b,
+        Arithmetic::Multiply => a * b,
+    };
+    // Results like inf or NaN cannot be stored as JSON
+    Number::from_f64(result)
+}
+
+fn to_big_int(number: &Number) -> Option<BigInt> {
+    // Numbers keep their digits (arbitrary_precision), so integers of any size parse
+    number.to_string().parse().ok()
+}
+
+pub fn normalize_numbers(value: &mut Value) {
+    /*
+     * Numbers keep the text they were read from (arbitrary_precision), which
+     * is what == and hashing compare, so 1.50 and 1.5 would differ. Every
+     * number is rewritten in the form arithmetic produces: floats through f64
+     * and integers as i64, u64 or a big integer.
+     */
+    match value {
+        Value::Number(number) => {
+            let normalized = if let Some(i) = number.as_i64() {
+                Some(Number::from(i))
+            } else if let Some(u) = number.as_u64() {
+                Some(Number::from(u))
+            } else if number.is_f64() {
+                number.as_f64().and_then(Number::from_f64)
+            } else {
+                to_big_int(number).map(big_int_to_number)
+            };
+            if let Some(normalized) = normalized {
+                *number = normalized;
+            }
+        }
+        Value::Array(elements) => elements.iter_mut().for_each(normalize_numbers),
+        Value::Object(map) => map.values_mut().for_each(normalize_numbers),
+        _ => {}
+    }
+}
+
+pub fn equality_key(value: &Value) -> Value {
+    // Copy of a value to hash, in which numbers that compare_values finds
+    // equal are written the same, e.g. 2.0 becomes 2
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            Some(float)
+                if number.is_f64() && float.fract() == 0.0 && float.abs() < i64::MAX as f64 =>
+            {
+                Value::from(float as i64)
+            }
+            _ => value.clone(),
+        },
+        Value::Array(elements) => Value::Array(elements.iter().map(equality_key).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.clone(), equality_key(value)))
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+fn big_int_to_number(big_int: BigInt) -> Number {
+    if let Ok(result) = i64::try_from(&big_int) {
+        return Number::from(result);
+    }
+    if let Ok(result) = u64::try_from(&big_int) {
+        return Number::from(result);
+    }
+    Number::from_string_unchecked(big_int.to_string())
+}
+
+pub fn compare_values(a: &Value, b: &Value) -> Ordering {
+    /*
+     * Total order of JSON values, similar to the BSON comparison order:
+     * null < numbers < strings < objects < arrays < booleans.
+     * Values of the same type are compared by value, strings lexicographically,
+     * and objects and arrays element by element.
+     */
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => compare_numbers(a, b),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare_values(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(a), Value::Object(b)) => a
+            .iter()
+            .zip(b)
+            .map(|((a_key, a), (b_key, b))| a_key.cmp(b_key).then_with(|| compare_values(a, b)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => type_order(a).cmp(&type_order(b)),
+    }
+}
+
+fn type_order(value: &Value) -> u8 {
+    // Position of the type of a value in the order used by compare_values
+    match value {
+        Value::Null => 0,
+        Value::Number(_) => 1,
+        Value::String(_) => 2,
+        Value::Object(_) => 3,
+        Value::Array(_) => 4,
+        Value::Bool(_) => 5,
+    }
+}
+
+fn compare_numbers(a: &Number, b: &Number) -> Ordering {
+    // Integers are compared exactly, anything else as floats
+    if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return a.cmp(&b);
+    }
+    if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return a.cmp(&b);
+    }
+    if !a.is_f64() && !b.is_f64() {
+        if let (Some(a), Some(b)) = (to_big_int(a), to_big_int(b)) {
+            return a.cmp(&b);
+        }
+    }
+    a.as_f64()
+        .partial_cmp(&b.as_f64())
+        .unwrap_or(Ordering::Equal)
+}
+
+fn value_type(value: &Value) -> &'static str {
+    // Name of the JSON type of a value, as used by the $type operator
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn parse_field_query(
+    mut fields: Vec<String>,
+    value: Value,
+    field_queries: &[(Vec<String>, Value)],
+) -> Result<Query<QueryOperator>, PyErr> {
+    /*
+     * Compiles the fields and value extracted by parse_query into a query
+     * on a single field, e.g. (["a", "$gt"], 10) into a > 10.
+     * field_queries holds every pair parsed from the same key, so operators
+     * like $regex can read sibling modifiers such as $options.
+     */
+    // if no '$' operator is found, assume it is an EqualOperator
+    // For example: {"a": 10} => a == 10
+    if !fields.last().unwrap().starts_with('$') {
+        return Ok(Query {
+            fields,
+            value,
+            operator: QueryOperator::Equal,
+        });
+    }
+    let query_op_str = fields.pop().unwrap();
+    let expects = |expected: &str| {
+        invalid_query(
+            &fields,
+            &format!(
+                "operator {} expects {}, found {}",
+                query_op_str, expected, value
+            ),
+        )
+    };
+    let operator = match query_op_str.as_str() {
+        "$not" => {
+            // {"a": {"$not": {"$gt": 10}}} negates {"a": {"$gt": 10}}
+            let Value::Object(map) = value else {
+                return Err(expects("an operator object"));
+            };
+            // Without fields, like in {"$elemMatch": {"$not": {"$gt": 10}}},
+            // the operators apply to the value itself
+            let negated_query = match fields.is_empty() {
+                true => map,
+                false => Map::from_iter([(fields.join("."), Value::Object(map))]),
+            };
+            return Ok(Query {
+                fields,
+                value: Value::Null,
+                operator: QueryOperator::Not(Box::new(QueryEngine::<QueryOperator>::parse(
+                    &negated_query,
+                )?)),
+            });
+        }
+        "$elemMatch" => {
+            // {"a": {"$elemMatch": {"b": 10}}} matches elements that are documents
+            // {"a": {"$elemMatch": {"$gt": 10}}} matches the elements themselves
+            let Value::Object(map) = &value else {
+                return Err(expects("a query"));
+            };
+            QueryOperator::ElemMatch(Box::new(QueryEngine::<QueryOperator>::parse(map)?))
+        }
+        "$regex" => {
+            let options = field_queries
+                .iter()
+                .find(|(option_fields, _)| {
+                    option_fields.last().unwrap() == "$options"
+                        && option_fields[..option_fields.len() - 1] == fields[..]
+                })
+                .map(|(_, options)| options);
+            QueryOperator::Regex(parse_regex(&fields, &value, options)?)
+        }
+        "$in" | "$nin" | "$all" if !value.is_array() => return Err(expects("an array")),
+        "$exists" if !value.is_boolean() => return Err(expects("a boolean")),
+        "$type"
+            if !matches!(
+                value.as_str(),
+                Some("string" | "number" | "bool" | "object" | "array" | "null")
+            ) =>
+        {
+            return Err(expects(
+                "one of string, number, bool, object, array or null",
+            ))
+        }
+        "$size" if !value.is_u64() => return Err(expects("a non-negative integer")),
+        "$startsWith" | "$endsWith" | "$contains" if !value.is_string() => {
+            return Err(expects("a string"))
+        }
+        _ => QueryOperator::from_str(&query_op_str)
+            .map_err(|_| invalid_query(&fields, &format!("unknown operator {}", query_op_str)))?,
+    };
+    Ok(Query {
+        fields,
+        value,
+        operator,
+    })
+}
+
+fn parse_regex(
+    fields: &[String],
+    pattern: &Value,
+    options: Option<&Value>,
+) -> Result<Regex, PyErr> {
+    /*
+     * Compiles a {"$regex": pattern, "$options": "i"} query. The options
+     * (i, m, s, x) are passed to the regex as inline flags, e.g. (?i)pattern.
+     */
+    let Some(pattern) = pattern.as_str() else {
+        return Err(invalid_query(
+            fields,
+            &format!("operator $regex expects a string, found {}", pattern),
+        ));
+    };
+    let pattern = match options {
+        Some(Value::String(flags)) if flags.chars().all(|c| "imsx".contains(c)) => {
+            if flags.is_empty() {
+                pattern.to_string()
+            } else {
+                format!("(?{}){}", flags, pattern)
+            }
+        }
+        Some(options) => {
+            return Err(invalid_query(
+                fields,
+                &format!("unknown $regex options {}", options),
+            ))
+        }
+        None => pattern.to_string(),
+    };
+    Regex::new(&pattern).map_err(|err| {
+        invalid_query(
+            fields,
+            &format!("operator $regex has an invalid pattern: {}", err),
+        )
+    })
+}
+
+pub fn parse_expression(expression: &Value) -> Result<Expression, PyErr> {
+    /*
+     * Compiles an $expr expression like {"$gt": ["$spent", {"$add": ["$budget", 10]}]}.
+     * Strings starting with '$' are field references, anything else is a literal.
+     */
+    match expression {
+        Value::String(field) if field.starts_with('$') => Ok(Expression::Field(
+            field[1..].split('.').map(|k| k.to_string()).collect(),
+        )),
+        Value::Object(map) if map.len() == 1 && map.keys().all(|k| k.starts_with('$')) => {
+            let (operator, arguments) = map.iter().next().unwrap();
+            let arguments: Vec<Expression> = match arguments {
+                Value::Array(arguments) => arguments
+                    .iter()
+                    .map(parse_expression)
+                    .collect::<Result<_, _>>()?,
+                argument => vec![parse_expression(argument)?],
+            };
+            match operator.as_str() {
+                "$add" => Ok(Expression::Add(arguments)),
+                "$multiply" => Ok(Expression::Multiply(arguments)),
+                "$subtract" if arguments.len() == 2 => Ok(Expression::Subtract(arguments)),
+                "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" if arguments.len() == 2 => Ok(
+                    Expression::Compare(QueryOperator::from_str(operator).unwrap(), arguments),
+                ),
+                "$subtract" | "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" => {
+                    Err(invalid_query(
+                        &[],
+                        &format!("operator {} in $expr expects two arguments", operator),
+                    ))
+                }
+                _ => Err(invalid_query(
+                    &[],
+                    &format!("unknown operator {} in $expr", operator),
+                )),
+            }
         }
+        _ => Ok(Expression::Literal(expression.clone())),
     }
 }
 
-fn parse_query(sub_query: &Value, key: &str, fields: &mut Vec<String>) -> Value {
+fn parse_logical_operator(key: &str, sub_query: &Value) -> Result<QueryOperator, PyErr> {
+    /*
+     * Compiles a top-level logical operator like {"$or": [{"a": 10}, {"b": 20}]}.
+     * Every element of the array is compiled into its own QueryEngine.
+     */
+    let expects_queries = || {
+        invalid_query(
+            &[],
+            &format!(
+                "operator {} expects an array of queries, found {}",
+                key, sub_query
+            ),
+        )
+    };
+    let Value::Array(sub_queries) = sub_query else {
+        return Err(expects_queries());
+    };
+    let engines: Vec<QueryEngine<QueryOperator>> = sub_queries
+        .iter()
+        .map(|q| match q {
+            Value::Object(map) => QueryEngine::<QueryOperator>::new(map),
+            _ => Err(expects_queries()),
+        })
+        .collect::<Result<_, _>>()?;
+    match key {
+        "$and" => Ok(QueryOperator::And(engines)),
+        "$or" => Ok(QueryOperator::Or(engines)),
+        _ => Ok(QueryOperator::Nor(engines)),
+    }
+}
+
+fn check_field_names(fields: &[String], path: &[String]) -> Result<(), PyErr> {
+    // Anything starting with '$' but positional segments like items.$ or
+    // items.$[] would be created as a field, e.g. the MongoDB form {"$set": {"a": 1}}
+    match path
+        .iter()
+        .find(|key| key.starts_with('$') && *key != "$" && !key.starts_with("$["))
+    {
+        Some(key) => Err(invalid_query(
+            fields,
+            &format!(
+                "field names cannot start with '$', found '{}'; operators follow the field they update, like {{\"a\": {{\"$set\": 1}}}}",
+                key
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn parse_update_query(
+    fields: Vec<String>,
+    value: Value,
+    update_op_str: &str,
+) -> Result<Query<UpdateOperator>, PyErr> {
+    /*
+     * Compiles the operand of an update operator. Array operators store the
+     * values to add as an array, so {"$push": 1} and {"$push": {"$each": [1]}}
+     * are executed the same way.
+     */
+    let expects = |expected: &str| {
+        invalid_query(
+            &fields,
+            &format!(
+                "operator {} expects {}, found {}",
+                update_op_str, expected, value
+            ),
+        )
+    };
+    let (value, operator) = match update_op_str {
+        "$push" | "$addToSet" => {
+            let is_push = update_op_str == "$push";
+            let mut modifiers = PushModifiers::default();
+            let values = match &value {
+                Value::Object(map) if map.keys().all(|k| k.starts_with('$')) => {
+                    let Some(Value::Array(each)) = map.get("$each") else {
+                        return Err(expects("an array in $each"));
+                    };
+                    for (modifier, modifier_value) in map {
+                        match modifier.as_str() {
+                            "$each" => {}
+                            "$slice" if is_push => {
+                                modifiers.slice = Some(
+                                    modifier_value
+                                        .as_i64()
+                                        .ok_or_else(|| expects("an integer in $slice"))?,
+                                );
+                            }
+                            "$sort" if is_push => {
+                                if !is_sort_order(modifier_value) {
+                                    return Err(expects("1, -1 or an object of them in $sort"));
+                                }
+                                modifiers.sort = Some(modifier_value.clone());
+                            }
+                            _ => {
+                                return Err(invalid_query(
+                                    &fields,
+                                    &format!(
+                                        "unknown modifier {} for operator {}",
+                                        modifier, update_op_str
+                                    ),
+                                ))
+                            }
+                        }
+                    }
+                    Value::Array(each.clone())
+                }
+                _ => Value::Array(vec![value.clone()]),
+            };
+            if is_push {
+                (values, UpdateOperator::Push(modifiers))
+            } else {
+                (values, UpdateOperator::AddToSet)
+            }
+        }
+        "$pull" => {
+            // {"$pull": 5} removes elements equal to 5,
+            // {"$pull": {"$gte": 5}} or {"$pull": {"sku": "a"}} the matching ones
+            let engine = match &value {
+                Value::Object(map) => QueryEngine::<QueryOperator>::parse(map)?,
+                _ => QueryEngine {
+                    queries: vec![Query {
+                        fields: Vec::new(),
+                        value: value.clone(),
+                        operator: QueryOperator::Equal,
+                    }],
+                },
+            };
+            (value, UpdateOperator::Pull(Box::new(engine)))
+        }
+        "$pop" if !matches!(value.as_i64(), Some(1 | -1)) => return Err(expects("1 or -1")),
+        // "" is still accepted as a step of 1
+        "$inc" | "$dec" if value == "" => {
+            let operator = UpdateOperator::from_str(update_op_str).unwrap();
+            (Value::from(1), operator)
+        }
+        "$inc" | "$dec" | "$add" | "$substract" | "$mul" if !value.is_number() => {
+            return Err(expects("a number"))
+        }
+        "$currentDate"
+            if value != Value::Bool(true)
+                && !matches!(
+                    value.as_object().map(|map| (map.len(), map.get("$type"))),
+                    Some((1, Some(Value::String(date_type))))
+                        if date_type == "date" || date_type == "timestamp"
+                ) =>
+        {
+            return Err(expects(
+                "true, {\"$type\": \"date\"} or {\"$type\": \"timestamp\"}",
+            ))
+        }
+        "$rename" => {
+            let Some(target) = value.as_str() else {
+                return Err(expects("a string"));
+            };
+            let target_fields: Vec<String> = target.split('.').map(|k| k.to_string()).collect();
+            // The target is a plain path, positional segments are not resolved in it
+            check_field_names(&fields, &target_fields)?;
+            if target_fields
+                .iter()
+                .any(|key| key.is_empty() || key.starts_with('$'))
+            {
+                return Err(invalid_query(
+                    &fields,
+                    &format!("cannot rename to '{}'", target),
+                ));
+            }
+            // A field cannot be moved into itself or one of its children
+            let (shorter, longer) = if target_fields.len() < fields.len() {
+                (&target_fields, &fields)
+            } else {
+                (&fields, &target_fields)
+            };
+            if target.is_empty() || longer.starts_with(shorter) {
+                return Err(invalid_query(
+                    &fields,
+                    &format!("cannot rename to '{}'", target),
+                ));
+            }
+            (value, UpdateOperator::Rename(target_fields))
+        }
+        _ => {
+            let operator = UpdateOperator::from_str(update_op_str).map_err(|_| {
+                invalid_query(
+                    &fields,
+                    &format!("unknown update operator {}", update_op_str),
+                )
+            })?;
+            (value, operator)
+        }
+    };
+    Ok(Query {
+        fields,
+        value,
+        operator,
+    })
+}
+
+fn take_path(collection: &mut Map<String, Value>, fields: &[String]) -> Option<Value> {
+    // Removes and returns the value at a path of objects
+    let (last_key, parent_fields) = fields.split_last().unwrap();
+    let mut parent = collection;
+    for key in parent_fields {
+        parent = parent.get_mut(key)?.as_object_mut()?;
+    }
+    parent.remove(last_key)
+}
+
+fn is_all_elements(key: &str) -> bool {
+    // Array segments that select several elements: *, $[] and $[identifier]
+    key == "*" || key == "$[]" || array_filter_identifier(key).is_some()
+}
+
+fn array_filter_identifier(key: &str) -> Option<&str> {
+    key.strip_prefix("$[")?
+        .strip_suffix(']')
+        .filter(|identifier| !identifier.is_empty())
+}
+
+fn is_sort_order(sort: &Value) -> bool {
+    match sort_fields(sort) {
+        Some(fields) => fields.iter().all(|(_, order)| is_sort_order(order)),
+        None => matches!(sort.as_i64(), Some(1 | -1)),
+    }
+}
+
+fn sort_fields(sort: &Value) -> Option<Vec<(&str, &Value)>> {
+    // Fields of a sort by document fields, either {"score": -1} or, when the
+    // order of the fields is kept, [["score", -1], ["name", 1]]
+    match sort {
+        Value::Object(map) if !map.is_empty() => Some(
+            map.iter()
+                .map(|(field, order)| (field.as_str(), order))
+                .collect(),
+        ),
+        Value::Array(pairs) if !pairs.is_empty() => pairs
+            .iter()
+            .map(|pair| match pair.as_array()?.as_slice() {
+                [Value::String(field), order] => Some((field.as_str(), order)),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+fn compare_with_sort(a: &Value, b: &Value, sort: &Value) -> Ordering {
+    // Sorts by the whole value ({"$sort": -1}) or by fields of
+    // documents ({"$sort": {"score": -1, "name": 1}})
+    let Some(fields) = sort_fields(sort) else {
+        let ordering = compare_values(a, b);
+        return if sort.as_i64() == Some(-1) {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+    };
+    for (field, order) in fields {
+        let fields: Vec<String> = field.split('.').map(|k| k.to_string()).collect();
+        let a_value = lookup_path(a, &fields).unwrap_or(&Value::Null);
+        let b_value = lookup_path(b, &fields).unwrap_or(&Value::Null);
+        let ordering = compare_with_sort(a_value, b_value, order);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+pub fn lookup_path<'a>(value: &'a Value, fields: &[String]) -> Option<&'a Value> {
+    fields.iter().try_fold(value, |current, key| match current {
+        Value::Object(map) => map.get(key),
+        Value::Array(elements) => key.parse::<usize>().ok().and_then(|i| elements.get(i)),
+        _ => None,
+    })
+}
+
+fn is_top_level_operator(key: &str) -> bool {
+    matches!(key, "$and" | "$or" | "$nor" | "$expr")
+}
+
+pub fn invalid_query(fields: &[String], reason: &str) -> PyErr {
+    // Error for a query that cannot be compiled, naming the path it was found at
+    if fields.is_empty() {
+        return InvalidQueryError::new_err(format!("Invalid query: {}", reason));
+    }
+    InvalidQueryError::new_err(format!(
+        "Invalid query at '{}': {}",
+        fields.join("."),
+        reason
+    ))
+}
+
+fn parse_query(
+    sub_query: &Value,
+    key: &str,
+    parent_fields: &[String],
+) -> Result<Vec<(Vec<String>, Value)>, PyErr> {
     /*
      * Parses a query recursively. It extracts the fields and value involved in a query.
      * When there is a nested query like {"a": {"b": 10} } it will extract the fields
      * ["a", "b"] to be able to follow the collection and return the value (10).
+     * Objects with several keys like {"a": {"$gte": 10, "$lt": 20} } return one
+     * entry per key: (["a", "$gte"], 10) and (["a", "$lt"], 20).
      */
-    if key.contains(".") {
-        let nested_fields = key.split(".").map(|k| k.to_string());
-        fields.extend(nested_fields)
-    } else {
-        fields.push(key.to_string());
-    }
+    let mut fields = parent_fields.to_vec();
+    fields.extend(key.split('.').map(|k| k.to_string()));
     match sub_query {
-        Value::Object(map) => map
-            .into_iter()
-            .find_map(|(key, val)| {
+        Value::Object(map) if !map.is_empty() => {
+            let mut parsed_queries = Vec::new();
+            for (key, val) in map {
                 // if the last element is an object, return that
                 // e.g, when setting an object {"a": {"$set": {"b": 30} }
                 // which would set {"a": {"b": 30} }
                 if key.starts_with('$') {
-                    fields.push(key.to_string());
-                    return Some(val.clone());
+                    let mut operator_fields = fields.clone();
+                    operator_fields.push(key.to_string());
+                    parsed_queries.push((operator_fields, val.clone()));
+                } else {
+                    parsed_queries.extend(parse_query(val, key, &fields)?);
                 }
-                Some(parse_query(val, key, fields))
-            })
-            .expect("Error while parsing query"),
-        Value::Bool(b) => Value::Bool(*b),
-        Value::Number(n) => Value::Number(n.clone()),
-        Value::String(s) => Value::String(s.to_string()),
-        _ => panic!("Not Valid query"),
+            }
+            Ok(parsed_queries)
+        }
+        Value::Object(_) => Err(invalid_query(
+            &fields,
+            "empty objects are not valid queries",
+        )),
+        _ => Ok(vec![(fields, sub_query.clone())]),
     }
 }
@@ -0,0 +1,119 @@
+use crate::query::{compare_values, invalid_query, lookup_path};
+use pyo3::PyErr;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+#[derive(Debug)]
+pub struct Sort {
+    // Dotted path of every sort field and whether it is descending
+    keys: Vec<(Vec<String>, bool)>,
+}
+
+impl Sort {
+    pub fn new(sort: &[(String, i64)]) -> Result<Self, PyErr> {
+        let mut keys = Vec::with_capacity(sort.len());
+        for (field, direction) in sort {
+            let fields: Vec<String> = field.split('.').map(|k| k.to_string()).collect();
+            let descending = match direction {
+                1 => false,
+                -1 => true,
+                _ => return Err(invalid_query(&fields, "sort direction must be 1 or -1")),
+            };
+            keys.push((fields, descending));
+        }
+        Ok(Sort { keys })
+    }
+
+    pub fn compare(&self, a: &Value, b: &Value) -> Ordering {
+        // Missing fields sort like null
+        for (fields, descending) in &self.keys {
+            let a_value = lookup_path(a, fields).unwrap_or(&Value::Null);
+            let b_value = lookup_path(b, fields).unwrap_or(&Value::Null);
+            let ordering = compare_values(a_value, b_value);
+            if ordering != Ordering::Equal {
+                return if *descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                };
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+struct HeapEntry<'a, 's> {
+    document: &'a Value,
+    // Documents that compare equal keep their order in the collection
+    position: usize,
+    sort: &'s Sort,
+}
+
+impl Ord for HeapEntry<'_, '_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort
+            .compare(self.document, other.document)
+            .then(self.position.cmp(&other.position))
+    }
+}
+
+impl PartialOrd for HeapEntry<'_, '_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry<'_, '_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry<'_, '_> {}
+
+pub fn select(
+    documents: &[Value],
+    sort: Option<&Sort>,
+    skip: usize,
+    limit: Option<usize>,
+) -> Vec<usize> {
+    // Positions of the documents of a page of results, in the order given by sort
+    let Some(sort) = sort else {
+        let positions = (0..documents.len()).skip(skip);
+        return match limit {
+            Some(limit) => positions.take(limit).collect(),
+            None => positions.collect(),
+        };
+    };
+    let Some(limit) = limit else {
+        let mut sorted: Vec<usize> = (0..documents.len()).collect();
+        sorted.sort_by(|a, b| sort.compare(&documents[*a], &documents[*b]));
+        return sorted.into_iter().skip(skip).collect();
+    };
+    // With a limit only the first skip + limit documents are kept, so the
+    // whole collection is not sorted for the first pages
+    let k = skip.saturating_add(limit);
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut heap: BinaryHeap<HeapEntry> = BinaryHeap::with_capacity(k.min(documents.len()) + 1);
+    for (position, document) in documents.iter().enumerate() {
+        let entry = HeapEntry {
+            document,
+            position,
+            sort,
+        };
+        if heap.len() < k {
+            heap.push(entry);
+        } else if entry < *heap.peek().unwrap() {
+            heap.pop();
+            heap.push(entry);
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .skip(skip)
+        .map(|entry| entry.position)
+        .collect()
+}
@@ -0,0 +1,246 @@
+import pytest
+from typing import Dict, Any, List
+from bison import Bison, InvalidQueryError
+import logging
+
+
+logger = logging.getLogger(__name__)
+
+SALES = [
+    {"_id": 1, "item": "apple", "price": 2, "qty": 10, "tags": ["fruit", "red"]},
+    {"_id": 2, "item": "pear", "price": 3, "qty": 5, "tags": ["fruit"]},
+    {"_id": 3, "item": "apple", "price": 2.5, "qty": 4, "tags": []},
+    {"_id": 4, "item": "bread", "price": 4, "qty": 1},
+    {"_id": 5, "item": "pear", "price": 3, "qty": 7, "tags": "sale"},
+]
+
+
+@pytest.mark.parametrize(
+    "pipeline, expected",
+    [
+        ([], SALES),
+        ([{"$match": {"item": "pear"}}], [SALES[1], SALES[4]]),
+        ([{"$match": {"qty": {"$gt": 4}}}, {"$count": "n"}], [{"n": 3}]),
+        ([{"$match": {"qty": {"$gt": 100}}}, {"$count": "n"}], []),
+        (
+            [{"$match": {"item": "bread"}}, {"$project": {"item": 1}}],
+            [{"_id": 4, "item": "bread"}],
+        ),
+        (
+            [{"$match": {"item": "bread"}}, {"$project": {"tags": 0, "_id": 0, "qty": 0}}],
+            [{"item": "bread", "price": 4}],
+        ),
+        (
+            [
+                {"$match": {"item": "bread"}},
+                {"$project": {"_id": 0, "item": 1, "total": {"$multiply": ["$price", "$qty"]}}},
+            ],
+            [{"item": "bread", "total": 4}],
+        ),
+        (
+            [{"$match": {"_id": 1}}, {"$project": {"item": 1, "tags": {"$slice": [1, 2**63]}}}],
+            [{"_id": 1, "item": "apple", "tags": ["red"]}],
+        ),
+        (
+            [{"$match": {"_id": 2}}, {"$project": {"_id": 0, "info.name": "$item", "kind": "food"}}],
+            [{"info": {"name": "pear"}, "kind": "food"}],
+        ),
+        (
+            [{"$sort": {"qty": -1}}, {"$skip": 1}, {"$limit": 2}, {"$project": {"qty": 1}}],
+            [{"_id": 5, "qty": 7}, {"_id": 2, "qty": 5}],
+        ),
+        (
+            [{"$sort": {"price": 1, "qty": -1}}, {"$limit": 3}, {"$project": {"_id": 1}}],
+            [{"_id": 1}, {"_id": 3}, {"_id": 5}],
+        ),
+        (
+            [{"$sort": {"item": 1, "qty": 1}}, {"$project": {"_id": 1}}],
+            [{"_id": 3}, {"_id": 1}, {"_id": 4}, {"_id": 2}, {"_id": 5}],
+        ),
+        # Sort fields are applied in the order they are given
+        (
+            [{"$sort": {"price": -1, "item": 1}}, {"$project": {"_id": 1}}],
+            [{"_id": 4}, {"_id": 2}, {"_id": 5}, {"_id": 3}, {"_id": 1}],
+        ),
+        (
+            [{"$unwind": "$tags"}, {"$project": {"tags": 1}}],
+            [
+                {"_id": 1, "tags": "fruit"},
+                {"_id": 1, "tags": "red"},
+                {"_id": 2, "tags": "fruit"},
+                {"_id": 5, "tags": "sale"},
+            ],
+        ),
+        (
+            [
+                {"$unwind": {"path": "$tags", "preserveNullAndEmptyArrays": True, "includeArrayIndex": "i"}},
+                {"$project": {"tags": 1, "i": 1}},
+            ],
+            [
+                {"_id": 1, "tags": "fruit", "i": 0},
+                {"_id": 1, "tags": "red", "i": 1},
+                {"_id": 2, "tags": "fruit", "i": 0},
+                {"_id": 3, "tags": [], "i": None},
+                {"_id": 4, "i": None},
+                {"_id": 5, "tags": "sale", "i": 0},
+            ],
+        ),
+    ],
+)
+def test_pipeline(
+    db: Bison, pipeline: List[Dict[str, Any]], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SALES)
+    assert db.aggregate(collection_name, pipeline) == expected
+
+
+def test_group(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SALES)
+    result = db.aggregate(
+        collection_name,
+        [
+            {
+                "$group": {
+                    "_id": "$item",
+                    "qty": {"$sum": "$qty"},
+                    "orders": {"$sum": 1},
+                    "avg_price": {"$avg": "$price"},
+                    "min_qty": {"$min": "$qty"},
+                    "max_price": {"$max": "$price"},
+                    "count": {"$count": {}},
+                    "ids": {"$push": "$_id"},
+                    "first": {"$first": "$qty"},
+                    "last": {"$last": "$qty"},
+                }
+            },
+            {"$sort": {"qty": -1}},
+        ],
+    )
+    assert result == [
+        {
+            "_id": "apple",
+            "qty": 14,
+            "orders": 2,
+            "avg_price": 2.25,
+            "min_qty": 4,
+            "max_price": 2.5,
+            "count": 2,
+            "ids": [1, 3],
+            "first": 10,
+            "last": 4,
+        },
+        {
+            "_id": "pear",
+            "qty": 12,
+            "orders": 2,
+            "avg_price": 3.0,
+            "min_qty": 5,
+            "max_price": 3,
+            "count": 2,
+            "ids": [2, 5],
+            "first": 5,
+            "last": 7,
+        },
+        {
+            "_id": "bread",
+            "qty": 1,
+            "orders": 1,
+            "avg_price": 4.0,
+            "min_qty": 1,
+            "max_price": 4,
+            "count": 1,
+            "ids": [4],
+            "first": 1,
+            "last": 1,
+        },
+    ]
+
+
+@pytest.mark.parametrize(
+    "group, expected",
+    [
+        (
+            {"_id": None, "revenue": {"$sum": {"$multiply": ["$price", "$qty"]}}},
+            [{"_id": None, "revenue": 70.0}],
+        ),
+        (
+            {"_id": {"item": "$item", "price": "$price"}, "n": {"$sum": 1}},
+            [
+                {"_id": {"item": "apple", "price": 2}, "n": 1},
+                {"_id": {"item": "pear", "price": 3}, "n": 2},
+                {"_id": {"item": "apple", "price": 2.5}, "n": 1},
+                {"_id": {"item": "bread", "price": 4}, "n": 1},
+            ],
+        ),
+        (
+            {"_id": "$missing", "avg": {"$avg": "$missing"}, "max": {"$max": "$missing"}},
+            [{"_id": None, "avg": None, "max": None}],
+        ),
+    ],
+)
+def test_group_keys(
+    db: Bison, group: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SALES)
+    assert db.aggregate(collection_name, [{"$group": group}]) == expected
+
+
+def test_group_equal_numbers(db: Bison) -> None:
+    # 2 and 2.0 are the same key, the group keeps the first one found
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [{"price": 2, "qty": 1}, {"price": 2.0, "qty": 2}, {"price": 2.5, "qty": 4}],
+    )
+    result = db.aggregate(
+        collection_name,
+        [{"$group": {"_id": "$price", "qty": {"$sum": "$qty"}}}],
+    )
+    assert result == [{"_id": 2, "qty": 3}, {"_id": 2.5, "qty": 4}]
+    assert type(result[0]["_id"]) is int
+
+
+def test_unwind_group(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SALES)
+    result = db.aggregate(
+        collection_name,
+        [
+            {"$match": {"tags": {"$exists": True}}},
+            {"$unwind": "$tags"},
+            {"$group": {"_id": "$tags", "qty": {"$sum": "$qty"}}},
+            {"$sort": {"_id": 1}},
+        ],
+    )
+    assert result == [
+        {"_id": "fruit", "qty": 15},
+        {"_id": "red", "qty": 10},
+        {"_id": "sale", "qty": 7},
+    ]
+
+
+@pytest.mark.parametrize(
+    "pipeline",
+    [
+        [{"$foo": {}}],
+        [{"$match": {"a": 1}, "$limit": 1}],
+        [{"$match": 1}],
+        [{"$match": {"a": {"$in": 1}}}],
+        [{"$limit": 0}],
+        [{"$skip": -1}],
+        [{"$sort": {"a": 2}}],
+        [{"$unwind": "tags"}],
+        [{"$count": "$n"}],
+        [{"$group": {"total": {"$sum": "$qty"}}}],
+        [{"$group": {"_id": None, "total": {"$median": "$qty"}}}],
+        [{"$project": {"a": 1, "b": 0}}],
+        [{"$project": {"a": 0, "b": "$qty"}}],
+    ],
+)
+def test_invalid_pipeline(db: Bison, pipeline: List[Dict[str, Any]]) -> None:
+    db.insert_many("test", SALES)
+    with pytest.raises(InvalidQueryError):
+        db.aggregate("test", pipeline)
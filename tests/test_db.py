@@ -1,6 +1,8 @@
 import os
 import pytest
-from bison import Bison
+import uuid
+from datetime import datetime
+from bison import Bison, UpdateError
 import json
 from pathlib import Path
 import logging
@@ -63,6 +65,8 @@ def test_load_from_existing(tmp_path: Path) -> None:
     db = Bison(str(tmp_path))
     for collection in db.collections():
         found = db.find(collection)
+        # pop id
+        found[0].pop("_id")
         assert found[0] == data_in_collection
 
 
@@ -109,3 +113,93 @@ def test_update_on_existing_db(tmp_path: Path) -> None:
         collection_name, {"a": {"$inc": ""}}, return_result=True)
 
     assert found_in_db == updated_collection
+
+
+def test_insert_assigns_id(db: Bison) -> None:
+    collection_name = "test"
+    document_id = db.insert(collection_name, {"a": 10})
+    assert uuid.UUID(document_id).version == 4
+    assert db.find(collection_name) == [{"_id": document_id, "a": 10}]
+
+
+def test_insert_many_returns_ids(db: Bison) -> None:
+    collection_name = "test"
+    ids = db.insert_many(collection_name, [{"a": 1}, {"_id": 7, "a": 2}, {"a": 3}])
+    assert len(ids) == 3
+    assert ids[1] == 7
+    assert len(set(ids)) == 3
+    assert [document["_id"] for document in db.find(collection_name)] == ids
+
+
+def test_duplicate_id(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"_id": "x", "a": 1})
+    with pytest.raises(ValueError):
+        db.insert(collection_name, {"_id": "x", "a": 2})
+    # Duplicates within a batch reject the whole batch
+    with pytest.raises(ValueError):
+        db.insert_many(collection_name, [{"_id": "y"}, {"_id": "y"}])
+    assert db.find(collection_name) == [{"_id": "x", "a": 1}]
+
+
+def test_insert_invalid_document(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"a": 1})
+    with pytest.raises(ValueError, match="Invalid document"):
+        db.insert(collection_name, {"a": datetime.now()})
+    with pytest.raises(ValueError, match="Invalid document"):
+        db.insert_many(collection_name, [{"a": 2}, {"a": datetime.now()}])
+    assert [document["a"] for document in db.find(collection_name)] == [1]
+
+
+def test_find_by_id(db: Bison) -> None:
+    collection_name = "test"
+    ids = db.insert_many(collection_name, [{"a": 1}, {"a": 2}])
+    assert db.find_by_id(collection_name, ids[1]) == {"_id": ids[1], "a": 2}
+    assert db.find_by_id(collection_name, "missing") is None
+
+
+def test_update_by_id(db: Bison) -> None:
+    collection_name = "test"
+    ids = db.insert_many(collection_name, [{"a": 1}, {"a": 2}])
+    updated = db.update_by_id(collection_name, ids[0], {"a": {"$inc": 5}})
+    assert updated == {"_id": ids[0], "a": 6}
+    assert db.find(collection_name, {"a": 6}) == [updated]
+    assert db.update_by_id(collection_name, "missing", {"a": {"$inc": 5}}) is None
+    with pytest.raises(UpdateError):
+        db.update_by_id(collection_name, ids[0], {"_id": {"$set": "other"}})
+    assert db.find_by_id(collection_name, ids[0]) == updated
+
+
+def test_delete_by_id(db: Bison) -> None:
+    collection_name = "test"
+    ids = db.insert_many(collection_name, [{"a": 1}, {"a": 2}, {"a": 3}])
+    assert db.delete_by_id(collection_name, ids[0]) == 1
+    assert db.delete_by_id(collection_name, ids[0]) == 0
+    # Documents are found by id after others have been removed
+    assert db.find_by_id(collection_name, ids[2]) == {"_id": ids[2], "a": 3}
+    db.delete_many(collection_name, {"a": 2})
+    assert db.find_by_id(collection_name, ids[2]) == {"_id": ids[2], "a": 3}
+
+
+def test_upsert_assigns_id(db: Bison) -> None:
+    collection_name = "test"
+    db.update(collection_name, {"a": {"$set": 1}}, {"b": 2}, upsert=True)
+    document = db.find(collection_name)[0]
+    assert db.find_by_id(collection_name, document["_id"]) == document
+
+
+def test_numbers_from_document(db: Bison, tmp_path: Path) -> None:
+    """Numbers read from a file compare equal however they are written"""
+    collection_name = "test"
+    document_path = os.path.join(tmp_path, "document.json")
+    with open(document_path, "w") as f:
+        f.write('[{"price": 1.50, "n": 1E2}]')
+    db.insert_many_from_document(collection_name, document_path)
+    db.insert(collection_name, {"price": 1.5, "n": 100})
+    assert len(db.find(collection_name, {"price": 1.5})) == 2
+    assert len(db.find(collection_name, {"price": {"$in": [1.5]}})) == 2
+    assert len(db.find(collection_name, {"n": 100.0})) == 2
+    assert db.distinct(collection_name, "price") == [1.5]
+    groups = db.aggregate(collection_name, [{"$group": {"_id": "$price", "n": {"$sum": 1}}}])
+    assert groups == [{"_id": 1.5, "n": 2}]
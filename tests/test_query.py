@@ -1,12 +1,22 @@
 import pytest
-from typing import Dict, Any
-from bison import Bison
+from typing import Dict, Any, List
+from bison import Bison, InvalidQueryError, UpdateError
 import logging
+import re
+from pathlib import Path
 
 
 logger = logging.getLogger(__name__)
 
 
+def without_ids(documents: List[Dict[str, Any]]) -> List[Dict[str, Any]]:
+    # Inserted documents get a generated _id, results are compared without it
+    return [
+        {key: value for key, value in document.items() if key != "_id"}
+        for document in documents
+    ]
+
+
 @pytest.mark.parametrize(
     "insert, query",
     [
@@ -22,7 +32,7 @@ def test_simple_eq_query(
     collection_name = "test"
     db.create_collection(collection_name)
     db.insert(collection_name, insert)
-    query_result = db.find(collection_name, query)
+    query_result = without_ids(db.find(collection_name, query))
     assert len(query_result) == 1
     # pop id
     query_result = query_result[0]
@@ -45,7 +55,7 @@ def test_eq_query(
     collection_name = "test"
     db.create_collection(collection_name)
     db.insert(collection_name, insert)
-    query_result = db.find(collection_name, filter_query)
+    query_result = without_ids(db.find(collection_name, filter_query))
     assert len(query_result) == 1
     # pop id
     query_result = query_result[0]
@@ -69,7 +79,7 @@ def test_ne_query(
     collection_name = "test"
     db.create_collection(collection_name)
     db.insert(collection_name, insert)
-    query_result = db.find(collection_name, filter_query)
+    query_result = without_ids(db.find(collection_name, filter_query))
     assert len(query_result) == 1
     # pop id
     query_result = query_result[0]
@@ -82,7 +92,7 @@ def test_gt_query(db: Bison):
     db.insert(collection_name, {"a": 20})
     db.insert(collection_name, {"a": 100})
     db.insert(collection_name, {"a": 101})
-    query_result = db.find(collection_name, {"a": {"$gt": 100}})
+    query_result = without_ids(db.find(collection_name, {"a": {"$gt": 100}}))
 
     assert len(query_result) == 1
     # pop id
@@ -96,7 +106,7 @@ def test_gte_query(db: Bison):
     db.insert(collection_name, {"a": 20})
     db.insert(collection_name, {"a": 100})
     db.insert(collection_name, {"a": 101})
-    query_result = db.find(collection_name, {"a": {"$gte": 101}})
+    query_result = without_ids(db.find(collection_name, {"a": {"$gte": 101}}))
 
     assert len(query_result) == 1
     # pop id
@@ -110,7 +120,7 @@ def test_lt_query(db: Bison):
     db.insert(collection_name, {"a": 20})
     db.insert(collection_name, {"a": 100})
     db.insert(collection_name, {"a": 101})
-    query_result = db.find(collection_name, {"a": {"$lt": 100}})
+    query_result = without_ids(db.find(collection_name, {"a": {"$lt": 100}}))
 
     assert len(query_result) == 1
     # pop id
@@ -124,7 +134,7 @@ def test_lte_query(db: Bison):
     db.insert(collection_name, {"a": 20})
     db.insert(collection_name, {"a": 100})
     db.insert(collection_name, {"a": 101})
-    query_result = db.find(collection_name, {"a": {"$lte": 20}})
+    query_result = without_ids(db.find(collection_name, {"a": {"$lte": 20}}))
 
     assert len(query_result) == 1
     # pop id
@@ -137,10 +147,10 @@ def test_mixed_queries(db: Bison):
     insert_value = {"a": {"myobj": 20}, "b": 20, "c": 120}
     db.insert(collection_name, insert_value)
 
-    query_result = db.find(
+    query_result = without_ids(db.find(
         "test", {"a": {"$eq": {"myobj": 20}},
                  "b": {"$gt": 19}, "c": {"$lte": 120}}
-    )
+    ))
     assert len(query_result) == 1
     query_result = query_result[0]
     assert query_result == insert_value
@@ -171,11 +181,11 @@ def test_simple_set_update(
     db.create_collection(collection_name)
     db.insert(collection_name, initial_value)
 
-    updated_db = db.update(collection_name, update_query, return_result=True)
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
     assert updated_db[0] == updated_value
 
     db.write_all()
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write == updated_db
 
 
@@ -186,19 +196,19 @@ def test_increment(db: Bison):
     insert_value = {"a": {"myobj": 20}, "b": 20, "c": {"d": 100}}
     db.insert(collection_name, insert_value)
 
-    updated_db = db.update(
-        collection_name, {"b": {"$inc": ""}}, return_result=True)
+    updated_db = without_ids(db.update(
+        collection_name, {"b": {"$inc": ""}}, return_result=True))
 
     assert updated_db[0]["b"] == insert_value["b"] + 1
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name, {"c": {"d": {"$inc": ""}}}, return_result=True
-    )
+    ))
 
     assert updated_db[0]["c"]["d"] == insert_value["c"]["d"] + 1
 
     db.write_all()
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write == updated_db
 
 
@@ -209,21 +219,21 @@ def test_decrement(db: Bison):
     insert_value = {"a": {"myobj": 20}, "b": 20, "c": {"d": 100}}
     db.insert(collection_name, insert_value)
 
-    updated_db = db.update(
-        collection_name, {"b": {"$dec": ""}}, return_result=True)
+    updated_db = without_ids(db.update(
+        collection_name, {"b": {"$dec": ""}}, return_result=True))
 
     # Assert changes in file
     assert updated_db[0]["b"] == insert_value["b"] - 1
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name, {"c": {"d": {"$dec": ""}}}, return_result=True
-    )
+    ))
 
     # Assert changes in file
     assert updated_db[0]["c"]["d"] == insert_value["c"]["d"] - 1
 
     db.write_all()
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write == updated_db
 
 
@@ -234,22 +244,22 @@ def test_add(db: Bison):
     insert_value = {"a": {"myobj": 20}, "b": 20, "c": {"d": 100}}
     db.insert(collection_name, insert_value)
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name, {"b": {"$add": add_value}}, return_result=True
-    )
+    ))
 
     # Assert changes in file
     assert updated_db[0]["b"] == insert_value["b"] + add_value
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name, {"c": {"d": {"$add": add_value}}}, return_result=True
-    )
+    ))
 
     # Assert changes in file
     assert updated_db[0]["c"]["d"] == insert_value["c"]["d"] + add_value
 
     db.write_all()
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write == updated_db
 
 
@@ -260,24 +270,24 @@ def test_substract(db: Bison):
     insert_value = {"a": {"myobj": 20}, "b": 20, "c": {"d": 100}}
     db.insert(collection_name, insert_value)
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name, {"b": {"$substract": substract_value}}, return_result=True
-    )
+    ))
 
     # Assert changes in file
     assert updated_db[0]["b"] == insert_value["b"] - substract_value
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name,
         {"c": {"d": {"$substract": substract_value}}},
         return_result=True,
-    )
+    ))
 
     # Assert changes in file
     assert updated_db[0]["c"]["d"] == insert_value["c"]["d"] - substract_value
 
     db.write_all()
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write == updated_db
 
 
@@ -288,20 +298,20 @@ def test_delete(db: Bison):
                     "b": 20, "c": {"d": 100}}
     db.insert(collection_name, insert_value)
 
-    updated_db = db.update(
-        collection_name, {"b": {"$delete": ""}}, return_result=True)
+    updated_db = without_ids(db.update(
+        collection_name, {"b": {"$delete": ""}}, return_result=True))
 
     # Assert changes in file
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write == updated_db
 
     assert len(updated_db) == 1
     updated_db = updated_db[0]
     assert "b" not in updated_db
 
-    updated_db = db.update(
+    updated_db = without_ids(db.update(
         collection_name, {"a": {"myobj": {"$delete": ""}}}, return_result=True
-    )
+    ))
 
     # Assert changes in file
     assert len(updated_db) == 1
@@ -309,20 +319,1372 @@ def test_delete(db: Bison):
     assert "myobj" not in updated_db["a"]
 
     db.write_all()
-    after_write = db.find(collection_name, {})
+    after_write = without_ids(db.find(collection_name, {}))
     assert after_write[0] == updated_db
 
 
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"a": {"$gt": "2024-01-15"}}, [{"a": "2024-02-01"}, {"a": "v1.10"}]),
+        ({"a": {"$lte": "2024-01-15"}}, [{"a": "2024-01-15"}, {"a": "2023-12-31"}]),
+        (
+            {"a": {"$lt": "b"}},
+            [{"a": "2024-02-01"}, {"a": "2024-01-15"}, {"a": "2023-12-31"}],
+        ),
+        ({"a": {"$gt": False}}, [{"a": True}]),
+        ({"a": {"$gte": None}}, [{"a": None}]),
+        ({"a": {"$lt": 100}}, [{"a": 10}, {"a": [1, 2]}]),
+        ({"a": {"$gt": [1]}}, [{"a": [1, 2]}]),
+    ],
+)
+def test_mixed_type_comparison(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    """Comparison operators only match values of the same type"""
+    db.insert_many(
+        "test",
+        [
+            {"a": "2024-02-01"},
+            {"a": "2024-01-15"},
+            {"a": "2023-12-31"},
+            {"a": "v1.10"},
+            {"a": 10},
+            {"a": True},
+            {"a": None},
+            {"a": [1, 2]},
+        ],
+    )
+    query_result = without_ids(db.find("test", query))
+    assert query_result == expected
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"$or": [{"a": 10}, {"b": 30}]}, [{"a": 10, "b": 20}, {"a": 20, "b": 30}]),
+        ({"$and": [{"a": {"$gt": 5}}, {"b": 20}]}, [{"a": 10, "b": 20}]),
+        ({"$nor": [{"a": 10}, {"b": 30}]}, [{"a": 30, "b": 40}]),
+        (
+            {"$or": [{"$and": [{"a": 10}, {"b": 20}]}, {"a": 30}]},
+            [{"a": 10, "b": 20}, {"a": 30, "b": 40}],
+        ),
+        ({"a": {"$not": {"$gt": 10}}}, [{"a": 10, "b": 20}]),
+        ({"$or": [{"a": 20}], "b": 40}, []),
+    ],
+)
+def test_logical_query(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.create_collection(collection_name)
+    db.insert_many(
+        collection_name,
+        [{"a": 10, "b": 20}, {"a": 20, "b": 30}, {"a": 30, "b": 40}],
+    )
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == expected
+
+
+def test_not_query_missing_field(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 20}, {"b": 20}])
+    query_result = without_ids(db.find(collection_name, {"a": {"$not": {"$gt": 10}}}))
+    assert query_result == [{"b": 20}]
+
+
+def test_logical_update_filter(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10}, {"a": 20}, {"a": 30}])
+    updated_db = without_ids(db.update(
+        collection_name,
+        {"a": {"$set": 0}},
+        {"$or": [{"a": 10}, {"a": 30}]},
+        return_result=True,
+    ))
+    assert updated_db == [{"a": 0}, {"a": 20}, {"a": 0}]
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"a": {"$in": [10, 30]}}, [{"a": 10}, {"a": 30, "b": True}]),
+        ({"a": {"$in": ["x", {"c": 1}]}}, [{"a": "x"}, {"a": {"c": 1}}]),
+        ({"b": {"$in": [True]}}, [{"a": 30, "b": True}]),
+        ({"a": {"$in": []}}, []),
+        (
+            {"a": {"$nin": [10, 30, "x"]}},
+            [{"a": {"c": 1}}, {"b": False}],
+        ),
+    ],
+)
+def test_membership_query(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [{"a": 10}, {"a": 30, "b": True}, {"a": "x"}, {"a": {"c": 1}}, {"b": False}],
+    )
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == expected
+
+
+def test_membership_update_filter(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10}, {"a": 20}, {"a": 30}])
+    updated_db = without_ids(db.update(
+        collection_name, {"a": {"$set": 0}}, {"a": {"$in": [10, 20]}}, return_result=True
+    ))
+    assert updated_db == [{"a": 0}, {"a": 0}, {"a": 30}]
+
+
+def test_membership_not_array(db: Bison) -> None:
+    db.insert("test", {"a": 10})
+    with pytest.raises(ValueError):
+        without_ids(db.find("test", {"a": {"$in": 10}}))
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"age": {"$gte": 18, "$lt": 65}}, [{"age": 18}, {"age": 40}]),
+        ({"age": {"$gt": 18, "$lte": 65}}, [{"age": 40}, {"age": 65}]),
+        ({"age": {"$gt": 10, "$ne": 40, "$lt": 65}}, [{"age": 18}]),
+        ({"age": {"$gte": 65, "$lt": 18}}, []),
+    ],
+)
+def test_range_query(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name, [{"age": 10}, {"age": 18}, {"age": 40}, {"age": 65}]
+    )
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == expected
+
+
+def test_multiple_field_update(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"a": {"b": 10, "c": 20}})
+    updated_db = without_ids(db.update(
+        collection_name,
+        {"a": {"b": {"$set": 0}, "c": {"$add": 5}}},
+        return_result=True,
+    ))
+    assert updated_db == [{"a": {"b": 0, "c": 25}}]
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"deleted_at": {"$exists": True}}, [{"deleted_at": 1}]),
+        (
+            {"deleted_at": {"$exists": False}},
+            [{"price": "10"}, {"price": 10.5, "meta": {"tags": []}}, {"meta": 1}],
+        ),
+        ({"meta.tags": {"$exists": True}}, [{"price": 10.5, "meta": {"tags": []}}]),
+        (
+            {"meta.tags": {"$exists": False}},
+            [{"price": "10"}, {"deleted_at": 1}, {"meta": 1}],
+        ),
+    ],
+)
+def test_exists_query(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [
+            {"price": "10"},
+            {"deleted_at": 1},
+            {"price": 10.5, "meta": {"tags": []}},
+            {"meta": 1},
+        ],
+    )
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == expected
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"a": {"$type": "string"}}, [{"a": "10"}]),
+        ({"a": {"$type": "number"}}, [{"a": 10}, {"a": 1.5}, {"a": [1]}]),
+        ({"a": {"$type": "bool"}}, [{"a": False}]),
+        ({"a": {"$type": "object"}}, [{"a": {"b": 1}}]),
+        ({"a": {"$type": "array"}}, [{"a": [1]}]),
+        ({"a": {"$type": "null"}}, [{"a": None}]),
+    ],
+)
+def test_type_query(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [
+            {"a": "10"},
+            {"a": 10},
+            {"a": 1.5},
+            {"a": False},
+            {"a": {"b": 1}},
+            {"a": [1]},
+            {"a": None},
+            {"b": 1},
+        ],
+    )
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == expected
+
+
 @pytest.mark.parametrize(
     "query",
     [
-        ({"a": {"$gt": False}}),
-        ({"b": {"$gte": []}}),
-        ({"c": {"$lt": False}}),
-        ({"d": {"$lte": False}}),
+        ({"a": {"$exists": 1}}),
+        ({"a": {"$type": "date"}}),
+    ],
+)
+def test_not_valid_exists_type_query(db: Bison, query: Dict[str, Any]) -> None:
+    db.insert("test", {"a": 10})
+    with pytest.raises(ValueError):
+        without_ids(db.find("test", query))
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"name": {"$regex": "^al"}}, [{"name": "alice", "email": "alice@example.com"}]),
+        (
+            {"name": {"$regex": "^al", "$options": "i"}},
+            [
+                {"name": "alice", "email": "alice@example.com"},
+                {"name": "Alba", "email": "alba@test.org"},
+            ],
+        ),
+        ({"email": {"$regex": r"@example\.com$"}}, [{"name": "alice", "email": "alice@example.com"}]),
+        ({"name": {"$startsWith": "Al"}}, [{"name": "Alba", "email": "alba@test.org"}]),
+        ({"email": {"$endsWith": ".org"}}, [{"name": "Alba", "email": "alba@test.org"}]),
+        ({"name": {"$contains": "b"}}, [{"name": "Alba", "email": "alba@test.org"}, {"name": "bob"}]),
+        ({"path": {"$contains": "b"}}, [{"name": 10, "path": ["b"]}]),
+    ],
+)
+def test_string_query(
+    db: Bison, query: Dict[str, Any], expected: List[Dict[str, Any]]
+) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [
+            {"name": "alice", "email": "alice@example.com"},
+            {"name": "Alba", "email": "alba@test.org"},
+            {"name": "bob"},
+            {"name": 10, "path": ["b"]},
+        ],
+    )
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == expected
+
+
+def test_regex_update_filter(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [{"path": "/usr/bin", "system": False}, {"path": "/home/bison", "system": False}],
+    )
+    updated_db = without_ids(db.update(
+        collection_name,
+        {"system": {"$set": True}},
+        {"path": {"$regex": "^/usr/"}},
+        return_result=True,
+    ))
+    assert updated_db == [
+        {"path": "/usr/bin", "system": True},
+        {"path": "/home/bison", "system": False},
+    ]
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"tags": "rust"}, [0, 1]),
+        ({"tags": ["rust", "db"]}, [0]),
+        ({"tags": {"$ne": "rust"}}, [2, 3]),
+        ({"tags": {"$in": ["db", "python"]}}, [0, 3]),
+        ({"tags": {"$nin": ["db", "python"]}}, [1, 2]),
+        ({"scores": {"$gt": 90}}, [0]),
+        ({"tags": {"$size": 2}}, [0]),
+        ({"tags": {"$size": 0}}, [2]),
+        ({"tags": {"$all": ["db", "rust"]}}, [0]),
+        ({"tags": {"$all": ["rust"]}}, [0, 1]),
+        ({"scores": {"$elemMatch": {"$gte": 80, "$lt": 85}}}, [1]),
+        ({"scores": {"$elemMatch": {"$not": {"$gt": 80}}}}, [0]),
+        ({"tags": {"$elemMatch": {"$regex": "^R", "$options": "i"}}}, [0]),
+        ({"tags": {"$elemMatch": {"$not": {"$regex": "^R", "$options": "i"}}}}, [0, 3]),
+        ({"items": {"$elemMatch": {"sku": "a", "qty": {"$gt": 1}}}}, [2]),
+        ({"items": {"$elemMatch": {"sku": "b", "qty": {"$gt": 1}}}}, []),
+    ],
+)
+def test_array_query(db: Bison, query: Dict[str, Any], expected: List[int]) -> None:
+    collection_name = "test"
+    documents = [
+        {"tags": ["rust", "db"], "scores": [95, 70]},
+        {"tags": "rust", "scores": [82, 88]},
+        {"tags": [], "items": [{"sku": "a", "qty": 2}, {"sku": "b", "qty": 1}]},
+        {"tags": ["python"], "items": []},
+    ]
+    db.insert_many(collection_name, documents)
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == [documents[ii] for ii in expected]
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"items.0.sku": "a"}, [0]),
+        ({"items.1.sku": "a"}, [1]),
+        ({"items.*.sku": "b"}, [0]),
+        ({"items.sku": "a"}, [0, 1]),
+        ({"items.qty": {"$gt": 4}}, [1]),
+        ({"items.5.sku": {"$exists": True}}, []),
+        ({"items.sku": {"$nin": ["a"]}}, [2]),
+        ({"matrix.1.0": 3}, [2]),
+    ],
+)
+def test_array_path_query(
+    db: Bison, query: Dict[str, Any], expected: List[int]
+) -> None:
+    collection_name = "test"
+    documents = [
+        {"items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}]},
+        {"items": [{"sku": "c", "qty": 5}, {"sku": "a", "qty": 3}]},
+        {"items": [], "matrix": [[1, 2], [3, 4]]},
+    ]
+    db.insert_many(collection_name, documents)
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == [documents[ii] for ii in expected]
+
+
+@pytest.mark.parametrize(
+    "update_query, updated_value",
+    [
+        (
+            {"items.0.qty": {"$set": 10}},
+            {"items": [{"sku": "a", "qty": 10}, {"sku": "b", "qty": 2}], "tags": ["x", "y"]},
+        ),
+        (
+            {"items.*.qty": {"$add": 5}},
+            {"items": [{"sku": "a", "qty": 6}, {"sku": "b", "qty": 7}], "tags": ["x", "y"]},
+        ),
+        (
+            {"tags.1": "z"},
+            {"items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}], "tags": ["x", "z"]},
+        ),
+        (
+            {"tags.0": {"$delete": ""}},
+            {"items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}], "tags": ["y"]},
+        ),
+        (
+            {"items.*.sku": {"$delete": ""}},
+            {"items": [{"qty": 1}, {"qty": 2}], "tags": ["x", "y"]},
+        ),
+        (
+            {"items.3.qty": {"$set": 10}},
+            {
+                "items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}, None, {"qty": 10}],
+                "tags": ["x", "y"],
+            },
+        ),
+    ],
+)
+def test_array_path_update(
+    db: Bison, update_query: Dict[str, Any], updated_value: Dict[str, Any]
+) -> None:
+    collection_name = "test"
+    db.insert(
+        collection_name,
+        {"items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}], "tags": ["x", "y"]},
+    )
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert updated_db == [updated_value]
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"$expr": {"$gt": ["$spent", "$budget"]}}, [0]),
+        ({"$expr": {"$ne": ["$updated_at", "$created_at"]}}, [1]),
+        ({"$expr": {"$eq": ["$updated_at", "$created_at"]}}, [0, 2]),
+        ({"$expr": {"$lte": ["$spent", {"$multiply": ["$budget", 0.5]}]}}, [1, 2]),
+        ({"$expr": {"$gte": [{"$add": ["$spent", 50]}, "$budget"]}}, [0, 1]),
+        ({"$expr": {"$lt": [{"$subtract": ["$budget", "$spent"]}, 100]}}, [0, 1]),
+        ({"$expr": {"$eq": ["$meta.owner", "alice"]}, "spent": {"$gt": 10}}, [1]),
+    ],
+)
+def test_expr_query(db: Bison, query: Dict[str, Any], expected: List[int]) -> None:
+    collection_name = "test"
+    documents = [
+        {"spent": 150, "budget": 100, "created_at": "2024-01", "updated_at": "2024-01"},
+        {
+            "spent": 50,
+            "budget": 100,
+            "created_at": "2024-01",
+            "updated_at": "2024-02",
+            "meta": {"owner": "alice"},
+        },
+        {"spent": 10, "budget": 300, "created_at": "2024-03", "updated_at": "2024-03"},
+    ]
+    db.insert_many(collection_name, documents)
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == [documents[ii] for ii in expected]
+
+
+def test_expr_update_filter(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [{"spent": 150, "budget": 100, "over": False}, {"spent": 50, "budget": 100, "over": False}],
+    )
+    updated_db = without_ids(db.update(
+        collection_name,
+        {"over": {"$set": True}},
+        {"$expr": {"$gt": ["$spent", "$budget"]}},
+        return_result=True,
+    ))
+    assert [doc["over"] for doc in updated_db] == [True, False]
+
+
+def test_expr_non_numeric_arithmetic(db: Bison) -> None:
+    db.insert("test", {"a": "10", "b": 5})
+    with pytest.raises(ValueError):
+        without_ids(db.find("test", {"$expr": {"$gt": [{"$add": ["$a", "$b"]}, 1]}}))
+
+
+@pytest.mark.parametrize(
+    "query, message",
+    [
+        ({"a": {"$foo": 10}}, "at 'a': unknown operator \\$foo"),
+        ({"a.b": {"$in": 10}}, "at 'a.b': operator \\$in expects an array"),
+        ({"a": {"$regex": "("}}, "at 'a': operator \\$regex has an invalid pattern"),
+        ({"a": {"$regex": "a", "$options": "q"}}, "unknown \\$regex options"),
+        ({"a": {"$options": "i"}}, "at 'a': \\$options requires a \\$regex"),
+        ({"a": {"$size": -1}}, "operator \\$size expects a non-negative integer"),
+        ({"a": {}}, "at 'a': empty objects are not valid queries"),
+        ({"$or": {"a": 10}}, "operator \\$or expects an array of queries"),
+        ({"$foo": [{"a": 10}]}, "unknown top-level operator \\$foo"),
+        ({"$expr": {"$gt": ["$a"]}}, "operator \\$gt in \\$expr expects two arguments"),
+    ],
+)
+def test_invalid_query(db: Bison, query: Dict[str, Any], message: str) -> None:
+    db.insert("test", {"a": 10})
+    with pytest.raises(InvalidQueryError, match=message):
+        without_ids(db.find("test", query))
+
+
+def test_invalid_update(db: Bison) -> None:
+    db.insert_many("test", [{"a": 10}, {"a": 20}])
+    with pytest.raises(InvalidQueryError, match="at 'a': unknown update operator \\$foo"):
+        db.update("test", {"a": {"$foo": 1}})
+    with pytest.raises(InvalidQueryError, match="at 'a': operator \\$in"):
+        db.update("test", {"a": {"$set": 0}}, {"a": {"$in": 10}})
+    assert without_ids(db.find("test")) == [{"a": 10}, {"a": 20}]
+
+
+def test_invalid_query_is_value_error() -> None:
+    assert issubclass(InvalidQueryError, ValueError)
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"manager": None}, [1, 2]),
+        ({"manager": {"$eq": None}}, [1, 2]),
+        ({"manager": {"$ne": None}}, [0]),
+        ({"manager": {"$in": [None, "bob"]}}, [0, 1, 2]),
+        ({"manager": {"$nin": [None]}}, [0]),
+        ({"manager": {"$ne": "bob"}}, [1, 2]),
+        ({"manager": {"$exists": True, "$eq": None}}, [1]),
+        ({"manager": {"$type": "null"}}, [1]),
+        ({"team.lead": None}, [1, 2]),
+    ],
+)
+def test_null_query(db: Bison, query: Dict[str, Any], expected: List[int]) -> None:
+    """Null matches explicit null values and missing fields"""
+    collection_name = "test"
+    documents = [
+        {"manager": "bob", "team": {"lead": "ann"}},
+        {"manager": None, "team": {"lead": None}},
+        {"team": {}},
+    ]
+    db.insert_many(collection_name, documents)
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == [documents[i] for i in expected]
+
+
+def test_set_null_update(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10, "b": 1}, {"a": 20, "b": 2}])
+    updated_db = without_ids(db.update(
+        collection_name, {"a": {"$set": None}}, {"b": 1}, return_result=True
+    ))
+    assert updated_db == [{"a": None, "b": 1}, {"a": 20, "b": 2}]
+    assert without_ids(db.find(collection_name, {"a": None})) == [{"a": None, "b": 1}]
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        ({"a": 1.0}, [0, 1]),
+        ({"a": {"$eq": 1}}, [0, 1]),
+        ({"a": {"$ne": 1.0}}, [2]),
+        ({"a": {"$in": [1.0, 5]}}, [0, 1]),
+        ({"a": {"$nin": [1]}}, [2]),
+        ({"a": {"$in": [[2, 3.0]]}}, [2]),
+        ({"a": {"$all": [1.0]}}, [0, 1]),
+        ({"a": {"$all": [3.0, 2]}}, [2]),
+    ],
+)
+def test_equal_numbers(db: Bison, query: Dict[str, Any], expected: List[int]) -> None:
+    # Integers and floats with the same value are equal
+    collection_name = "test"
+    documents = [{"a": 1}, {"a": 1.0}, {"a": [2.0, 3]}]
+    db.insert_many(collection_name, documents)
+    query_result = without_ids(db.find(collection_name, query))
+    assert query_result == [documents[ii] for ii in expected]
+
+
+def test_elem_match_not(db: Bison) -> None:
+    collection_name = "test"
+    documents = [{"a": [1, 5, 9]}, {"a": [2]}, {"a": [5, 9]}, {"a": 3}]
+    db.insert_many(collection_name, documents)
+    query_result = without_ids(
+        db.find(collection_name, {"a": {"$elemMatch": {"$not": {"$gt": 4}}}})
+    )
+    assert query_result == documents[:2]
+
+
+@pytest.mark.parametrize(
+    "update_query, expected",
+    [
+        ({"history": {"$push": 4}}, [3, 1, 2, 4]),
+        ({"history": {"$push": [4]}}, [3, 1, 2, [4]]),
+        ({"history": {"$push": {"$each": [4, 5]}}}, [3, 1, 2, 4, 5]),
+        ({"history": {"$push": {"$each": [0], "$sort": 1}}}, [0, 1, 2, 3]),
+        ({"history": {"$push": {"$each": [4], "$slice": -2}}}, [2, 4]),
+        ({"history": {"$push": {"$each": [], "$sort": -1, "$slice": 2}}}, [3, 2]),
+        ({"history": {"$addToSet": 3}}, [3, 1, 2]),
+        ({"history": {"$addToSet": {"$each": [2, 5, 5]}}}, [3, 1, 2, 5]),
+        ({"history": {"$addToSet": 1.0}}, [3, 1, 2]),
+        ({"history": {"$addToSet": {"$each": [5, 5.0]}}}, [3, 1, 2, 5]),
+        ({"history": {"$pop": 1}}, [3, 1]),
+        ({"history": {"$pop": -1}}, [1, 2]),
+        ({"history": {"$pull": 1}}, [3, 2]),
+        ({"history": {"$pull": {"$gte": 2}}}, [1]),
+        ({"history": {"$pull": {"$in": [1, 3]}}}, [2]),
+        ({"history": {"$pull": {"$not": {"$gt": 1}}}}, [3, 2]),
+    ],
+)
+def test_array_update(
+    db: Bison, update_query: Dict[str, Any], expected: List[Any]
+) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"history": [3, 1, 2]})
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert updated_db == [{"history": expected}]
+
+
+def test_pull_regex(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"tags": ["Rust", "db", "rusty"]})
+    update_query = {"tags": {"$pull": {"$regex": "^r", "$options": "i"}}}
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert updated_db == [{"tags": ["db"]}]
+
+
+def test_array_update_documents(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(
+        collection_name,
+        {"items": [{"sku": "a", "qty": 2}, {"sku": "b", "qty": 1}]},
+    )
+    updated_db = without_ids(db.update(
+        collection_name,
+        {
+            "items": {
+                "$push": {"$each": [{"sku": "c", "qty": 5}], "$sort": {"qty": -1}}
+            }
+        },
+        return_result=True,
+    ))
+    assert [item["sku"] for item in updated_db[0]["items"]] == ["c", "a", "b"]
+    updated_db = without_ids(db.update(
+        collection_name, {"items": {"$pull": {"qty": {"$lt": 3}}}}, return_result=True
+    ))
+    assert updated_db == [{"items": [{"sku": "c", "qty": 5}]}]
+
+
+def test_array_update_sort_fields_order(db: Bison) -> None:
+    collection_name = "test"
+    ids = db.insert_many(
+        collection_name,
+        [{"scores": [{"name": "b", "score": 1}, {"name": "c", "score": 2}]}],
+    )
+    # Sort fields are applied in the order they are given
+    update_query = {
+        "scores": {
+            "$push": {
+                "$each": [{"name": "a", "score": 2}],
+                "$sort": {"score": -1, "name": 1},
+            }
+        }
+    }
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert [item["name"] for item in updated_db[0]["scores"]] == ["a", "c", "b"]
+    updated = db.update_by_id(
+        collection_name,
+        ids[0],
+        {"scores": {"$push": {"$each": [], "$sort": {"score": 1, "name": -1}}}},
+    )
+    assert [item["name"] for item in updated["scores"]] == ["b", "c", "a"]
+
+
+@pytest.mark.parametrize(
+    "update_query",
+    [
+        ({"history": {"$pop": 2}}),
+        ({"history": {"$push": {"$each": 4}}}),
+        ({"history": {"$push": {"$each": [4], "$slice": "2"}}}),
+        ({"history": {"$push": {"$each": [4], "$sort": "asc"}}}),
+        ({"history": {"$push": {"$each": [4], "$sort": {"a": 1, "b": 2}}}}),
+        ({"history": {"$addToSet": {"$each": [4], "$slice": 1}}}),
+    ],
+)
+def test_invalid_array_update(db: Bison, update_query: Dict[str, Any]) -> None:
+    db.insert("test", {"history": [3, 1, 2]})
+    with pytest.raises(InvalidQueryError):
+        db.update("test", update_query)
+
+
+@pytest.mark.parametrize(
+    "update_query, updated_value",
+    [
+        ({"b": {"$set": 20}}, {"a": 10, "b": 20}),
+        ({"b": None}, {"a": 10, "b": None}),
+        ({"stats.views": {"$inc": ""}}, {"a": 10, "stats": {"views": 1}}),
+        ({"stats.views": {"$dec": ""}}, {"a": 10, "stats": {"views": -1}}),
+        ({"stats.total": {"$add": 5}}, {"a": 10, "stats": {"total": 5}}),
+        ({"stats.total": {"$substract": 5}}, {"a": 10, "stats": {"total": -5}}),
+        ({"a.b.c": {"$delete": ""}}, {"a": 10}),
+        ({"history": {"$push": 1}}, {"a": 10, "history": [1]}),
+        ({"tags": {"$addToSet": "x"}}, {"a": 10, "tags": ["x"]}),
+        ({"history": {"$pop": 1}}, {"a": 10}),
+        ({"history": {"$pull": 1}}, {"a": 10}),
+    ],
+)
+def test_update_missing_field(
+    db: Bison, update_query: Dict[str, Any], updated_value: Dict[str, Any]
+) -> None:
+    """Updates create missing fields and intermediate objects"""
+    collection_name = "test"
+    db.insert(collection_name, {"a": 10})
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert updated_db == [updated_value]
+
+
+def test_update_through_non_object(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": {"b": {}}}, {"_id": 2, "a": 10}])
+    with pytest.raises(UpdateError, match="Cannot update 'a.b.c' in document {\"_id\":2,\"a\":10}: 'a' is a number"):
+        db.update(collection_name, {"a.b.c": {"$set": 1}})
+    # No document is updated when one of them fails
+    assert without_ids(db.find(collection_name)) == [{"a": {"b": {}}}, {"a": 10}]
+
+
+@pytest.mark.parametrize(
+    "update_query, filter_query, expected",
+    [
+        (
+            {"visits": {"$inc": ""}},
+            {"user": "bob"},
+            [{"user": "ann", "visits": 3}, {"user": "bob", "visits": 1}],
+        ),
+        (
+            {"visits": {"$inc": ""}, "created": {"$setOnInsert": "2024-01-01"}},
+            {"user": "ann"},
+            [{"user": "ann", "visits": 4}],
+        ),
+        (
+            {"visits": {"$inc": ""}, "created": {"$setOnInsert": "2024-01-01"}},
+            {"$and": [{"user": {"$eq": "bob"}}, {"age": {"$gt": 18}}]},
+            [
+                {"user": "ann", "visits": 3},
+                {"user": "bob", "visits": 1, "created": "2024-01-01"},
+            ],
+        ),
+        (
+            {"visits": {"$set": 0}},
+            {"profile.name": "bob"},
+            [{"user": "ann", "visits": 3}, {"profile": {"name": "bob"}, "visits": 0}],
+        ),
+    ],
+)
+def test_upsert(
+    db: Bison,
+    update_query: Dict[str, Any],
+    filter_query: Dict[str, Any],
+    expected: List[Dict[str, Any]],
+) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"user": "ann", "visits": 3})
+    db.update(collection_name, update_query, filter_query, upsert=True)
+    assert without_ids(db.find(collection_name)) == expected
+
+
+def test_upsert_new_collection(db: Bison) -> None:
+    db.update("new", {"a": {"$set": 1}}, {"b": 2}, upsert=True)
+    assert without_ids(db.find("new")) == [{"b": 2, "a": 1}]
+
+
+@pytest.mark.parametrize(
+    "update_query, updated_value",
+    [
+        ({"name": {"$rename": "title"}}, {"title": "a", "stats": {"low": 5, "high": 10}}),
+        (
+            {"stats.low": {"$rename": "low"}},
+            {"name": "a", "stats": {"high": 10}, "low": 5},
+        ),
+        (
+            {"missing": {"$rename": "other"}},
+            {"name": "a", "stats": {"low": 5, "high": 10}},
+        ),
+        ({"stats.low": {"$min": 2}}, {"name": "a", "stats": {"low": 2, "high": 10}}),
+        ({"stats.low": {"$min": 8}}, {"name": "a", "stats": {"low": 5, "high": 10}}),
+        ({"stats.high": {"$max": 20}}, {"name": "a", "stats": {"low": 5, "high": 20}}),
+        ({"stats.high": {"$max": 8}}, {"name": "a", "stats": {"low": 5, "high": 10}}),
+        (
+            {"stats.mid": {"$max": 7}},
+            {"name": "a", "stats": {"low": 5, "high": 10, "mid": 7}},
+        ),
+        ({"stats.high": {"$mul": 1.5}}, {"name": "a", "stats": {"low": 5, "high": 15}}),
+        (
+            {"stats.mid": {"$mul": 2}},
+            {"name": "a", "stats": {"low": 5, "high": 10, "mid": 0}},
+        ),
+    ],
+)
+def test_field_update_operators(
+    db: Bison, update_query: Dict[str, Any], updated_value: Dict[str, Any]
+) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"name": "a", "stats": {"low": 5, "high": 10}})
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert updated_db == [updated_value]
+
+
+def test_current_date_update(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"a": 1})
+    updated_db = without_ids(db.update(
+        collection_name,
+        {
+            "updated": {"$currentDate": True},
+            "seen": {"$currentDate": {"$type": "timestamp"}},
+        },
+        return_result=True,
+    ))
+    document = updated_db[0]
+    assert re.fullmatch(r"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z", document["updated"])
+    assert isinstance(document["seen"], int) and document["seen"] > 1_600_000_000_000
+
+
+@pytest.mark.parametrize(
+    "update_query",
+    [
+        ({"a": {"$rename": 1}}),
+        ({"a": {"$rename": "a.b"}}),
+        ({"a.b": {"$rename": "a"}}),
+        ({"a.b": {"$rename": "$x"}}),
+        ({"a": {"$rename": "b.$x"}}),
+        ({"a": {"$rename": "b.$"}}),
+        ({"a": {"$rename": "b..c"}}),
+        ({"a": {"$mul": "2"}}),
+        ({"a": {"$currentDate": {"$type": "time"}}}),
+    ],
+)
+def test_invalid_field_update(db: Bison, update_query: Dict[str, Any]) -> None:
+    db.insert("test", {"a": 1})
+    with pytest.raises(InvalidQueryError):
+        db.update("test", update_query)
+    assert without_ids(db.find("test")) == [{"a": 1}]
+
+
+@pytest.mark.parametrize(
+    "value, update_query, expected",
+    [
+        (1, {"a": {"$inc": ""}}, 2),
+        (1, {"a": {"$dec": ""}}, 0),
+        (10, {"a": {"$add": 5}}, 15),
+        (10, {"a": {"$substract": 15}}, -5),
+        (10, {"a": {"$mul": 3}}, 30),
+        (2**53, {"a": {"$inc": ""}}, 2**53 + 1),
+        (2**63 - 1, {"a": {"$inc": ""}}, 2**63),
+        (2**64 - 1, {"a": {"$add": 1}}, 2**64),
+        (-(2**63), {"a": {"$dec": ""}}, -(2**63) - 1),
+        (2**62, {"a": {"$mul": 2**62}}, 2**124),
+        (1, {"a": {"$add": 0.5}}, 1.5),
+        (1.5, {"a": {"$inc": ""}}, 2.5),
+    ],
+)
+def test_integer_arithmetic(
+    db: Bison, value: Any, update_query: Dict[str, Any], expected: Any
+) -> None:
+    """Integers stay integers of any size, floats only come from floats"""
+    collection_name = "test"
+    db.insert(collection_name, {"a": value})
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    result = updated_db[0]["a"]
+    assert result == expected
+    assert type(result) is type(expected)
+
+
+def test_big_integer_persistence(tmp_path: Path) -> None:
+    db = Bison(str(tmp_path))
+    db.insert("test", {"a": 2**64 - 1})
+    db.update("test", {"a": {"$mul": 10}})
+    db.write_all()
+    del db
+
+    db = Bison(str(tmp_path))
+    assert without_ids(db.find("test")) == [{"a": (2**64 - 1) * 10}]
+    assert without_ids(db.find("test", {"a": {"$gt": 2**64}})) == [{"a": (2**64 - 1) * 10}]
+
+
+def test_non_finite_arithmetic(db: Bison) -> None:
+    db.insert("test", {"a": 1e308})
+    with pytest.raises(UpdateError, match="Cannot update 'a'"):
+        db.update("test", {"a": {"$mul": 10.0}})
+    assert without_ids(db.find("test")) == [{"a": 1e308}]
+
+
+@pytest.mark.parametrize(
+    "update_query, expected",
+    [
+        ({"a": {"$inc": 5}}, 15),
+        ({"a": {"$inc": -3}}, 7),
+        ({"a": {"$inc": 0.5}}, 10.5),
+        ({"a": {"$inc": ""}}, 11),
+        ({"a": {"$dec": 4}}, 6),
+        ({"a": {"$dec": ""}}, 9),
+        ({"b": {"$inc": 5}}, 10),
     ],
 )
-def test_not_valid_query(db: Bison, query: Dict[str, Any]) -> None:
+def test_increment_step(
+    db: Bison, update_query: Dict[str, Any], expected: Any
+) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"a": 10})
+    updated_db = without_ids(db.update(collection_name, update_query, return_result=True))
+    assert updated_db[0]["a"] == expected
+    if "b" in update_query:
+        assert updated_db[0]["b"] == 5
+
+
+@pytest.mark.parametrize(
+    "update_query",
+    [
+        ({"a": {"$inc": "5"}}),
+        ({"a": {"$dec": None}}),
+        ({"a": {"$add": "5"}}),
+        ({"a": {"$substract": [1]}}),
+    ],
+)
+def test_invalid_arithmetic_operand(db: Bison, update_query: Dict[str, Any]) -> None:
     db.insert("test", {"a": 10})
+    with pytest.raises(InvalidQueryError, match="expects a number"):
+        db.update("test", update_query)
+
+
+@pytest.mark.parametrize(
+    "update_query, message",
+    [
+        ({"name": {"$inc": 1}}, "Cannot update 'name' in document .*: \\$inc expects a number"),
+        ({"name": {"$add": 1}}, "\\$add expects a number, found \"bob\""),
+        ({"tags.*": {"$mul": 2}}, "Cannot update 'tags.\\*' .*: \\$mul expects a number"),
+        ({"empty": {"$dec": 1}}, "\\$dec expects a number, found null"),
+    ],
+)
+def test_arithmetic_on_non_number(
+    db: Bison, update_query: Dict[str, Any], message: str
+) -> None:
+    documents = [{"n": 1}, {"name": "bob", "tags": [1, "x"], "empty": None}]
+    db.insert_many("test", documents)
+    with pytest.raises(UpdateError, match=message):
+        db.update("test", update_query)
+    assert without_ids(db.find("test")) == documents
+
+
+@pytest.mark.parametrize(
+    "update_query, message",
+    [
+        ({"name": {"$push": 1}}, "Cannot update 'name' in document .*: \\$push expects an array, found \"bob\""),
+        ({"n": {"$addToSet": 1}}, "\\$addToSet expects an array, found 1"),
+        ({"empty": {"$pop": 1}}, "\\$pop expects an array, found null"),
+        ({"meta": {"$pull": {"$gt": 1}}}, "Cannot update 'meta' .*: \\$pull expects an array"),
+    ],
+)
+def test_array_update_on_non_array(
+    db: Bison, update_query: Dict[str, Any], message: str
+) -> None:
+    documents = [{"tags": [1]}, {"name": "bob", "n": 1, "empty": None, "meta": {"a": 2}}]
+    db.insert_many("test", documents)
+    with pytest.raises(UpdateError, match=message):
+        db.update("test", update_query)
+    assert without_ids(db.find("test")) == documents
+
+
+def test_pull_condition_error(db: Bison) -> None:
+    documents = [{"items": [{"a": 1}, {"a": 2}]}]
+    db.insert_many("test", documents)
+    condition = {"$expr": {"$gt": [{"$add": ["$a", "x"]}, 0]}}
+    with pytest.raises(UpdateError, match="Cannot update 'items' .*: \\$pull condition failed"):
+        db.update("test", {"items": {"$pull": condition}})
+    assert without_ids(db.find("test")) == documents
+
+
+ORDER = {
+    "items": [
+        {"sku": "a", "qty": 1, "price": 10},
+        {"sku": "b", "qty": 2, "price": 20},
+        {"sku": "c", "qty": 5, "price": 30},
+    ],
+    "tags": ["x", "y"],
+}
+
+
+@pytest.mark.parametrize(
+    "update_query, filter_query, array_filters, expected_qty",
+    [
+        ({"items.$.qty": {"$inc": 10}}, {"items.sku": "b"}, None, [1, 12, 5]),
+        (
+            {"items.$.qty": 0},
+            {"items": {"$elemMatch": {"qty": {"$gt": 1}, "price": {"$lt": 30}}}},
+            None,
+            [1, 0, 5],
+        ),
+        ({"items.$[].qty": {"$mul": 2}}, None, None, [2, 4, 10]),
+        (
+            {"items.$[item].qty": {"$set": 0}},
+            None,
+            [{"item.qty": {"$gte": 2}}],
+            [1, 0, 0],
+        ),
+        (
+            {"items.$[item].qty": {"$inc": 1}},
+            None,
+            [{"item.qty": {"$gte": 2}, "item.price": {"$lt": 30}}],
+            [1, 3, 5],
+        ),
+    ],
+)
+def test_positional_update(
+    db: Bison,
+    update_query: Dict[str, Any],
+    filter_query: Dict[str, Any],
+    array_filters: List[Dict[str, Any]],
+    expected_qty: List[int],
+) -> None:
+    collection_name = "test"
+    db.insert(collection_name, ORDER)
+    updated_db = without_ids(db.update(
+        collection_name,
+        update_query,
+        filter_query,
+        return_result=True,
+        array_filters=array_filters,
+    ))
+    assert [item["qty"] for item in updated_db[0]["items"]] == expected_qty
+
+
+def test_positional_update_scalars(db: Bison) -> None:
+    collection_name = "test"
+    db.insert(collection_name, {"tags": ["x", "y", "z"]})
+    updated_db = without_ids(db.update(
+        collection_name, {"tags.$": "w"}, {"tags": "y"}, return_result=True
+    ))
+    assert updated_db == [{"tags": ["x", "w", "z"]}]
+    updated_db = without_ids(db.update(
+        collection_name,
+        {"tags.$[tag]": {"$delete": ""}},
+        return_result=True,
+        array_filters=[{"tag": {"$in": ["x", "z"]}}],
+    ))
+    assert updated_db == [{"tags": ["w"]}]
+
+
+@pytest.mark.parametrize(
+    "update_query, filter_query, array_filters, error",
+    [
+        ({"items.$.qty": 0}, None, None, UpdateError),
+        ({"items.$.qty": 0}, {"tags": "x"}, None, UpdateError),
+        ({"items.$.tags.$": 0}, {"items.sku": "a"}, None, InvalidQueryError),
+        ({"items.$[item].qty": 0}, None, None, InvalidQueryError),
+        ({"items.$[item].qty": 0}, None, [{"other.qty": 1}], InvalidQueryError),
+        ({"items.$[].qty": 0}, None, [{"item.qty": 1}], InvalidQueryError),
+    ],
+)
+def test_invalid_positional_update(
+    db: Bison,
+    update_query: Dict[str, Any],
+    filter_query: Dict[str, Any],
+    array_filters: List[Dict[str, Any]],
+    error: type,
+) -> None:
+    db.insert("test", ORDER)
+    with pytest.raises(error):
+        db.update("test", update_query, filter_query, array_filters=array_filters)
+
+
+@pytest.mark.parametrize(
+    "filter_query, expected_count, expected",
+    [
+        ({"a": {"$gte": 20}}, 2, [{"a": 10}]),
+        ({"a": 40}, 0, [{"a": 10}, {"a": 20}, {"a": 30}]),
+        ({}, 3, []),
+    ],
+)
+def test_delete_many(
+    db: Bison,
+    filter_query: Dict[str, Any],
+    expected_count: int,
+    expected: List[Dict[str, Any]],
+) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10}, {"a": 20}, {"a": 30}])
+    assert db.delete_many(collection_name, filter_query) == expected_count
+    assert without_ids(db.find(collection_name)) == expected
+
+
+def test_delete_one(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10}, {"a": 20}, {"a": 30}])
+    assert db.delete_one(collection_name, {"a": {"$gte": 20}}) == 1
+    assert without_ids(db.find(collection_name)) == [{"a": 10}, {"a": 30}]
+    assert db.delete_one(collection_name, {"a": 40}) == 0
+
+
+def test_delete_invalidates_cache(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10}, {"a": 20}])
+    assert without_ids(db.find(collection_name, {"a": 20})) == [{"a": 20}]
+    db.delete_many(collection_name, {"a": 20})
+    assert without_ids(db.find(collection_name, {"a": 20})) == []
+
+
+def test_delete_invalid_query(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, [{"a": 10}, {"a": "x"}])
+    with pytest.raises(InvalidQueryError):
+        db.delete_many(collection_name, {"a": {"$in": 10}})
+    assert without_ids(db.find(collection_name)) == [{"a": 10}, {"a": "x"}]
+
+
+PROJECTED = {
+    "_id": 1,
+    "name": "bob",
+    "address": {"city": "Paris", "zip": "75001"},
+    "payload": "x" * 100,
+    "items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}, 5],
+    "scores": [1, 2, 3, 4, 5],
+}
+
+
+@pytest.mark.parametrize(
+    "projection, expected",
+    [
+        ({"name": 1}, {"_id": 1, "name": "bob"}),
+        ({"name": True, "_id": 0}, {"name": "bob"}),
+        ({"_id": 1}, {"_id": 1}),
+        ({"address.city": 1}, {"_id": 1, "address": {"city": "Paris"}}),
+        ({"items.sku": 1}, {"_id": 1, "items": [{"sku": "a"}, {"sku": "b"}]}),
+        ({"name.first": 1, "_id": 0}, {}),
+        (
+            {"payload": 0, "items": 0, "scores": 0},
+            {"_id": 1, "name": "bob", "address": {"city": "Paris", "zip": "75001"}},
+        ),
+        (
+            {"_id": 0, "payload": 0, "address.zip": 0, "items.qty": 0, "scores": 0},
+            {
+                "name": "bob",
+                "address": {"city": "Paris"},
+                "items": [{"sku": "a"}, {"sku": "b"}, 5],
+            },
+        ),
+        ({"name": 1, "scores": {"$slice": 2}}, {"_id": 1, "name": "bob", "scores": [1, 2]}),
+        ({"scores": {"$slice": -2}, "_id": 0, "name": 1}, {"name": "bob", "scores": [4, 5]}),
+        ({"scores": {"$slice": [1, 2]}, "_id": 0, "name": 1}, {"name": "bob", "scores": [2, 3]}),
+        ({"scores": {"$slice": [-2, 5]}, "_id": 0, "name": 1}, {"name": "bob", "scores": [4, 5]}),
+        ({"scores": {"$slice": 10}, "_id": 0, "name": 1}, {"name": "bob", "scores": [1, 2, 3, 4, 5]}),
+        ({"scores": {"$slice": [1, 2**63]}, "_id": 0, "name": 1}, {"name": "bob", "scores": [2, 3, 4, 5]}),
+        ({"scores": {"$slice": [-1, 2**64 - 1]}, "_id": 0, "name": 1}, {"name": "bob", "scores": [5]}),
+        (
+            {"scores": {"$slice": 1}, "payload": 0},
+            {
+                "_id": 1,
+                "name": "bob",
+                "address": {"city": "Paris", "zip": "75001"},
+                "items": [{"sku": "a", "qty": 1}, {"sku": "b", "qty": 2}, 5],
+                "scores": [1],
+            },
+        ),
+    ],
+)
+def test_projection(
+    db: Bison, projection: Dict[str, Any], expected: Dict[str, Any]
+) -> None:
+    collection_name = "test"
+    db.insert(collection_name, PROJECTED)
+    assert db.find(collection_name, {"name": "bob"}, projection) == [expected]
+    assert db.find(collection_name, projection=projection) == [expected]
+
+
+@pytest.mark.parametrize(
+    "projection",
+    [
+        {"name": 1, "payload": 0},
+        {"name": "yes"},
+        {"scores": {"$slice": "2"}},
+        {"scores": {"$slice": [1, 0]}},
+        {"scores": {"$elemMatch": {"$gt": 1}}},
+        {"address": 1, "address.city": 1},
+    ],
+)
+def test_invalid_projection(db: Bison, projection: Dict[str, Any]) -> None:
+    db.insert("test", PROJECTED)
+    with pytest.raises(InvalidQueryError):
+        db.find("test", {}, projection)
+
+
+SORTED = [
+    {"_id": 0, "name": "carol", "age": 30},
+    {"_id": 1, "name": "alice", "age": 25},
+    {"_id": 2, "name": "bob", "age": 30},
+    {"_id": 3, "name": "dave"},
+    {"_id": 4, "name": "erin", "age": 25, "score": {"total": 7}},
+]
+
+
+@pytest.mark.parametrize(
+    "sort, limit, skip, expected",
+    [
+        ([("name", 1)], None, 0, [1, 2, 0, 3, 4]),
+        ([("name", -1)], None, 0, [4, 3, 0, 2, 1]),
+        # Missing fields sort like null, ties keep insertion order
+        ([("age", 1)], None, 0, [3, 1, 4, 0, 2]),
+        ([("age", -1), ("name", 1)], None, 0, [2, 0, 1, 4, 3]),
+        ([("age", -1), ("name", 1)], 2, 0, [2, 0]),
+        ([("age", -1), ("name", 1)], 2, 1, [0, 1]),
+        ([("age", 1)], 3, 0, [3, 1, 4]),
+        ([("age", 1)], None, 3, [0, 2]),
+        ([("score.total", -1)], 1, 0, [4]),
+        ([("name", 1)], 10, 4, [4]),
+        ([("name", 1)], 0, 0, []),
+        (None, 2, 0, [0, 1]),
+        (None, 2, 2, [2, 3]),
+        (None, None, 4, [4]),
+    ],
+)
+def test_sort_limit_skip(
+    db: Bison,
+    sort: Any,
+    limit: Any,
+    skip: int,
+    expected: List[int],
+) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    query_result = db.find(collection_name, sort=sort, limit=limit, skip=skip)
+    assert [document["_id"] for document in query_result] == expected
+
+
+def test_sort_with_query_and_projection(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    query_result = db.find(
+        collection_name,
+        {"age": {"$exists": True}},
+        {"_id": 0, "name": 1},
+        sort=[("name", -1)],
+        limit=2,
+    )
+    assert query_result == [{"name": "erin"}, {"name": "carol"}]
+
+
+def test_invalid_sort(db: Bison) -> None:
+    db.insert_many("test", SORTED)
+    with pytest.raises(InvalidQueryError, match="sort direction"):
+        db.find("test", sort=[("name", 2)])
+
+
+def test_find_iter(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    cursor = db.find_iter(collection_name, {"age": {"$exists": True}})
+    assert len(cursor) == 4
+    assert next(cursor) == SORTED[0]
+    assert len(cursor) == 3
+    assert list(cursor) == SORTED[1:3] + SORTED[4:]
+    assert list(cursor) == []
+    assert list(db.find_iter(collection_name)) == db.find(collection_name)
+
+
+def test_find_iter_options(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    cursor = db.find_iter(
+        collection_name,
+        projection={"name": 1, "_id": 0},
+        sort=[("name", 1)],
+        skip=1,
+        limit=3,
+    )
+    assert list(cursor) == [{"name": "bob"}, {"name": "carol"}, {"name": "dave"}]
+
+
+def test_find_iter_batches(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    batches = list(db.find_iter(collection_name, batch_size=2))
+    assert batches == [SORTED[0:2], SORTED[2:4], SORTED[4:]]
     with pytest.raises(ValueError):
-        db.find("test", {"a": {"$gt": False}})
+        db.find_iter(collection_name, batch_size=0)
+
+
+@pytest.mark.parametrize("query", [None, {"name": {"$ne": "dave"}}])
+def test_find_iter_snapshot(db: Bison, query: Any) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    cursor = db.find_iter(collection_name, query, limit=1)
+    # Changes after the cursor is created are not seen by it
+    db.update(collection_name, {"name": {"$set": "zoe"}})
+    db.delete_many(collection_name, {})
+    assert list(cursor) == SORTED[:1]
+
+
+def test_find_iter_close(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    cursor = db.find_iter(collection_name)
+    assert next(cursor) == SORTED[0]
+    cursor.close()
+    assert len(cursor) == 0
+    assert list(cursor) == []
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        (None, SORTED[0]),
+        ({"age": 25}, SORTED[1]),
+        ({"name": "dave"}, SORTED[3]),
+        ({"age": 40}, None),
+    ],
+)
+def test_find_one(db: Bison, query: Any, expected: Any) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    assert db.find_one(collection_name, query) == expected
+
+
+def test_find_one_projection(db: Bison) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    assert db.find_one(collection_name, {"age": 30}, {"_id": 0, "name": 1}) == {
+        "name": "carol"
+    }
+
+
+@pytest.mark.parametrize(
+    "query, expected",
+    [
+        (None, 5),
+        ({}, 5),
+        ({"age": 30}, 2),
+        ({"age": {"$exists": False}}, 1),
+        ({"age": 40}, 0),
+    ],
+)
+def test_count(db: Bison, query: Any, expected: int) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    assert db.count(collection_name, query) == expected
+
+
+@pytest.mark.parametrize(
+    "field, query, expected",
+    [
+        ("age", None, [30, 25]),
+        ("age", {"name": {"$ne": "carol"}}, [25, 30]),
+        ("score.total", None, [7]),
+        ("tags", None, ["a", "b", "c", ["d"]]),
+        ("items.sku", None, ["x", "y"]),
+        ("color", None, [None, "red"]),
+        ("missing", None, []),
+    ],
+)
+def test_distinct(
+    db: Bison, field: str, query: Any, expected: List[Any]
+) -> None:
+    collection_name = "test"
+    db.insert_many(collection_name, SORTED)
+    db.insert_many(
+        collection_name,
+        [
+            {"tags": ["a", "b"], "items": [{"sku": "x"}, {"sku": "y"}], "color": None},
+            {"tags": ["b", "c", ["d"]], "items": [{"sku": "x"}], "color": "red"},
+        ],
+    )
+    assert db.distinct(collection_name, field, query) == expected
+
+
+def test_distinct_numbers(db: Bison) -> None:
+    # 1 and 1.0 are the same value, True is not a number
+    collection_name = "test"
+    db.insert_many(
+        collection_name,
+        [
+            {"a": 1},
+            {"a": 1.0},
+            {"a": True},
+            {"a": [2.0, 2, 1]},
+            {"a": [[3, 4.0]]},
+            {"a": [[3.0, 4]]},
+        ],
+    )
+    values = db.distinct(collection_name, "a")
+    assert values == [1, True, 2.0, [3, 4.0]]
+    assert [type(value) for value in values] == [int, bool, float, list]
+
+
+def test_count_invalid_query(db: Bison) -> None:
+    db.insert_many("test", SORTED)
+    with pytest.raises(InvalidQueryError):
+        db.count("test", {"age": {"$in": 30}})
+    with pytest.raises(InvalidQueryError):
+        db.distinct("test", "age", {"age": {"$in": 30}})
+
+
+@pytest.mark.parametrize(
+    "update_query",
+    [
+        {"$set": {"a": 1}},
+        {"$inc": {"a": 1}},
+        {"a.$foo.b": {"$set": 1}},
+    ],
+)
+def test_update_operator_as_field(db: Bison, update_query: Dict[str, Any]) -> None:
+    db.insert("test", {"_id": 1, "a": 0})
+    with pytest.raises(InvalidQueryError, match="cannot start with '\\$'"):
+        db.update("test", update_query)
+    assert db.find("test") == [{"_id": 1, "a": 0}]